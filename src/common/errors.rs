@@ -35,4 +35,28 @@ impl std::fmt::Display for ErrorStr {
     }
 }
 
-impl std::error::Error for ErrorStr {}
\ No newline at end of file
+impl std::error::Error for ErrorStr {}
+
+/// Error that sets the process exit code, other errors exit with code 1.
+#[derive(Debug)]
+pub struct ExitError {
+    code: i32,
+    msg: String,
+}
+
+impl ExitError {
+    pub fn new(code: i32, msg: String) -> Box<Self> {
+        Box::new(Self { code, msg })
+    }
+    pub fn code(&self) -> i32 {
+        self.code
+    }
+}
+
+impl std::fmt::Display for ExitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl std::error::Error for ExitError {}
@@ -1,4 +1,5 @@
 pub(crate) mod errors;
+pub(crate) mod output;
 
 pub trait Command {
     fn create() -> Box<Self> where Self: Sized;
@@ -24,6 +25,10 @@ impl Dispatcher {
         }
         self
     }
+    /// Options shared by all commands, added to the root application only.
+    pub fn fill_global_args<'a, 'b>(&self, app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+        output::add_format_arg(app)
+    }
     pub fn fill_subcommands<'a, 'b>(&self, mut app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
         for (_, cmd) in &self.m_commands {
             app = cmd.fill_subcommand(app);
@@ -0,0 +1,256 @@
+use std::io::Write;
+
+/// Name of the global option selecting the output format.
+pub const FORMAT_ARG: &str = "format";
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum EFormat {
+    EText,
+    EJson,
+    ECsv,
+    ETable,
+}
+
+impl EFormat {
+    pub fn from_args(args: Option<&clap::ArgMatches>) -> Self {
+        match args.and_then(|a| a.value_of(FORMAT_ARG)) {
+            Some("json") => EFormat::EJson,
+            Some("csv") => EFormat::ECsv,
+            Some("table") => EFormat::ETable,
+            _ => EFormat::EText,
+        }
+    }
+}
+
+pub fn add_format_arg<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+    app.arg(
+        clap::Arg::with_name(FORMAT_ARG)
+            .long(FORMAT_ARG)
+            .takes_value(true)
+            .global(true)
+            .possible_values(&["text", "json", "csv", "table"])
+            .help("output format, text by default, other formats print only records and skip headers and summaries"))
+}
+
+pub enum Value {
+    Str(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    List(Vec<Value>),
+    Null,
+}
+
+impl Value {
+    fn to_text(&self) -> String {
+        match self {
+            Value::Str(s) => s.clone(),
+            Value::Int(i) => i.to_string(),
+            Value::Float(f) => format!("{:.3}", f),
+            Value::Bool(b) => b.to_string(),
+            Value::List(items) => items.iter().map(|i| i.to_text()).collect::<Vec<String>>().join(" "),
+            Value::Null => String::new(),
+        }
+    }
+    fn to_json(&self) -> String {
+        match self {
+            Value::Str(s) => json_string(s),
+            Value::Float(f) if !f.is_finite() => String::from("null"),
+            Value::List(items) => format!("[{}]", items.iter().map(|i| i.to_json()).collect::<Vec<String>>().join(", ")),
+            Value::Null => String::from("null"),
+            _ => self.to_text(),
+        }
+    }
+    fn to_csv(&self) -> String {
+        csv_field(&self.to_text())
+    }
+}
+
+impl From<&str> for Value {
+    fn from(v: &str) -> Self { Value::Str(String::from(v)) }
+}
+
+impl From<String> for Value {
+    fn from(v: String) -> Self { Value::Str(v) }
+}
+
+impl From<&String> for Value {
+    fn from(v: &String) -> Self { Value::Str(v.clone()) }
+}
+
+impl From<bool> for Value {
+    fn from(v: bool) -> Self { Value::Bool(v) }
+}
+
+impl From<f32> for Value {
+    fn from(v: f32) -> Self { Value::Float(v as f64) }
+}
+
+impl From<f64> for Value {
+    fn from(v: f64) -> Self { Value::Float(v) }
+}
+
+macro_rules! int_value {
+    ($($t:ty),*) => {
+        $(impl From<$t> for Value {
+            fn from(v: $t) -> Self { Value::Int(v as i64) }
+        })*
+    };
+}
+
+int_value!(i32, i64, u16, u32, u64, usize);
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(v: Option<T>) -> Self { v.map_or(Value::Null, |v| v.into()) }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(v: Vec<T>) -> Self { Value::List(v.into_iter().map(|i| i.into()).collect()) }
+}
+
+/// One printed item, named fields for the structured formats and a line for the text format.
+pub struct Record {
+    text: Option<String>,
+    fields: Vec<(&'static str, Value)>,
+}
+
+impl Record {
+    pub fn new() -> Self {
+        Self { text: None, fields: Vec::new() }
+    }
+    pub fn field<V: Into<Value>>(mut self, name: &'static str, value: V) -> Self {
+        self.fields.push((name, value.into()));
+        self
+    }
+    /// Line printed in the text format, the field values separated by spaces if it is not set.
+    pub fn text<S: Into<String>>(mut self, text: S) -> Self {
+        self.text = Some(text.into());
+        self
+    }
+    fn value(&self, name: &str) -> Option<&Value> {
+        self.fields.iter().find(|(n, _)| *n == name).map(|(_, v)| v)
+    }
+}
+
+pub fn csv_field(value: &str) -> String {
+    if !value.contains([',', '"', '\n', '\r']) { return String::from(value); }
+    format!("\"{}\"", value.replace('"', "\"\""))
+}
+
+pub fn json_string(value: &str) -> String {
+    let mut result = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+/// Prints records in the chosen format. JSON is an array of objects, CSV and tables have a column for every
+/// field name found in the records. CSV and tables are printed and JSON arrays are closed when the output is dropped.
+pub struct Output {
+    format: EFormat,
+    writer: Box<dyn Write + Send>,
+    records: usize,
+    // CSV and table rows, printed at the end when all columns are known
+    rows: Vec<Record>,
+}
+
+impl Output {
+    /// Output to stdout in the format given by the global option.
+    pub fn new(args: Option<&clap::ArgMatches>) -> Self {
+        Self::with_writer(EFormat::from_args(args), Box::new(std::io::stdout()))
+    }
+    /// Output to stderr, for commands that write data to stdout.
+    pub fn stderr(args: Option<&clap::ArgMatches>) -> Self {
+        Self::with_writer(EFormat::from_args(args), Box::new(std::io::stderr()))
+    }
+    pub fn with_writer(format: EFormat, writer: Box<dyn Write + Send>) -> Self {
+        Self { format, writer, records: 0, rows: Vec::new() }
+    }
+    pub fn is_text(&self) -> bool {
+        self.format == EFormat::EText
+    }
+    /// Headers, summaries and other lines which are not records, printed only in the text format.
+    pub fn text<S: std::fmt::Display>(&mut self, line: S) {
+        if self.is_text() { let _ = writeln!(self.writer, "{}", line); }
+    }
+    pub fn record(&mut self, record: Record) {
+        let _ = match self.format {
+            EFormat::EText => {
+                let text = record.text.clone().unwrap_or_else(|| {
+                    record.fields.iter().map(|(_, v)| v.to_text()).collect::<Vec<String>>().join(" ")
+                });
+                writeln!(self.writer, "{}", text)
+            }
+            EFormat::EJson => {
+                let fields: Vec<String> = record.fields.iter()
+                    .map(|(n, v)| format!("{}: {}", json_string(n), v.to_json()))
+                    .collect();
+                // the separator of the previous object goes first, the array is closed on drop
+                let separator = if self.records == 0 { "[" } else { "," };
+                write!(self.writer, "{}\n  {{{}}}", separator, fields.join(", "))
+            }
+            EFormat::ECsv | EFormat::ETable => {
+                self.rows.push(record);
+                Ok(())
+            }
+        };
+        self.records += 1;
+    }
+    /// Field names of all rows in the order they first appear.
+    fn columns(&self) -> Vec<&'static str> {
+        let mut columns = Vec::<&'static str>::new();
+        for row in self.rows.iter() {
+            for (name, _) in row.fields.iter() {
+                if !columns.contains(name) { columns.push(name); }
+            }
+        }
+        columns
+    }
+    fn print_csv(&mut self) {
+        let columns = self.columns();
+        let _ = writeln!(self.writer, "{}", columns.join(","));
+        for row in self.rows.iter() {
+            let values: Vec<String> = columns.iter().map(|n| row.value(n).map_or(String::new(), |v| v.to_csv())).collect();
+            let _ = writeln!(self.writer, "{}", values.join(","));
+        }
+    }
+    fn print_table(&mut self) {
+        let columns = self.columns();
+        let cells: Vec<Vec<String>> = self.rows.iter()
+            .map(|row| columns.iter().map(|n| row.value(n).map_or(String::new(), |v| v.to_text())).collect())
+            .collect();
+        let widths: Vec<usize> = columns.iter().enumerate()
+            .map(|(i, n)| cells.iter().map(|row| row[i].chars().count()).chain(std::iter::once(n.len())).max().unwrap())
+            .collect();
+
+        let header: Vec<String> = columns.iter().zip(widths.iter()).map(|(n, w)| format!("{:<w$}", n, w = w)).collect();
+        let _ = writeln!(self.writer, "{}", header.join("  ").trim_end());
+        for row in cells.iter() {
+            let line: Vec<String> = row.iter().zip(widths.iter()).map(|(c, w)| format!("{:<w$}", c, w = w)).collect();
+            let _ = writeln!(self.writer, "{}", line.join("  ").trim_end());
+        }
+    }
+}
+
+impl Drop for Output {
+    fn drop(&mut self) {
+        match self.format {
+            EFormat::EJson => { let _ = writeln!(self.writer, "{}", if self.records == 0 { "[]" } else { "\n]" }); }
+            EFormat::ECsv if !self.rows.is_empty() => self.print_csv(),
+            EFormat::ETable if !self.rows.is_empty() => self.print_table(),
+            _ => {}
+        }
+        let _ = self.writer.flush();
+    }
+}
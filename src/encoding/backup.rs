@@ -0,0 +1,141 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::common;
+use crate::common::output::{Output, Record};
+
+const JOURNAL_NAME: &str = "wtool_backup_journal.txt";
+
+fn journal_path() -> PathBuf {
+    std::env::temp_dir().join(JOURNAL_NAME)
+}
+
+fn append_extension(path: &Path, extension: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(extension);
+    path.with_file_name(name)
+}
+
+fn absolute_path(path: &Path) -> std::io::Result<PathBuf> {
+    if path.is_absolute() { Ok(path.to_path_buf()) } else { Ok(std::env::current_dir()?.join(path)) }
+}
+
+/// List of files changed by the last run with backups, used by `encoding restore`.
+/// Each line is either `backup\t<target>\t<backup>` or `created\t<target>`.
+pub(super) struct Journal {
+    file: std::fs::File,
+}
+
+impl Journal {
+    /// Starts a new journal, forgetting the previous run.
+    pub(super) fn create() -> std::io::Result<Self> {
+        Ok(Self { file: std::fs::File::create(journal_path())? })
+    }
+    fn record(&self, line: String) -> std::io::Result<()> {
+        let mut file = &self.file;
+        writeln!(file, "{}", line)?;
+        file.flush()
+    }
+    /// Copies the current content of `path` to `<path>.bak` before it is overwritten,
+    /// `<path>.bak.1`, `<path>.bak.2`, ... if the name is taken, so existing files are never replaced.
+    fn backup(&self, path: &Path) -> std::io::Result<()> {
+        let path = absolute_path(path)?;
+        if !path.exists() {
+            return self.record(format!("created\t{}", path.display()));
+        }
+        let mut backup = append_extension(&path, ".bak");
+        let mut number = 0;
+        while backup.exists() {
+            number += 1;
+            backup = append_extension(&path, &format!(".bak.{}", number));
+        }
+        std::fs::copy(&path, &backup)?;
+        self.record(format!("backup\t{}\t{}", path.display(), backup.display()))
+    }
+}
+
+fn write_tmp<F>(tmp: &Path, write: F) -> Result<(), Box<dyn std::error::Error>>
+    where F: FnOnce(&mut dyn Write) -> Result<(), Box<dyn std::error::Error>> {
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(tmp)?);
+    write(&mut writer)?;
+    writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    Ok(())
+}
+
+/// Lets `write` fill a temporary file next to `path` and renames it over `path`,
+/// so the target is either fully written or left untouched.
+pub(super) fn write_with<F>(path: &Path, journal: Option<&Journal>, write: F) -> Result<(), Box<dyn std::error::Error>>
+    where F: FnOnce(&mut dyn Write) -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = append_extension(path, ".wtool.tmp");
+    let mut result = write_tmp(&tmp, write);
+    // the new content keeps the mode of the file it replaces
+    if let (true, Ok(meta)) = (result.is_ok(), std::fs::metadata(path)) {
+        result = std::fs::set_permissions(&tmp, meta.permissions()).map_err(|e| e.into());
+    }
+    if let (true, Some(journal)) = (result.is_ok(), journal) {
+        result = journal.backup(path).map_err(|e| e.into());
+    }
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+        return result;
+    }
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+pub(super) fn write_file(path: &Path, data: &[u8], journal: Option<&Journal>) -> Result<(), Box<dyn std::error::Error>> {
+    write_with(path, journal, |writer| Ok(writer.write_all(data)?))
+}
+
+pub struct RestoreCmd;
+
+impl RestoreCmd {
+    fn restore(line: &str) -> std::io::Result<Record> {
+        let parts: Vec<&str> = line.split('\t').collect();
+        match parts[..] {
+            ["backup", target, backup] => {
+                std::fs::rename(backup, target)?;
+                Ok(Record::new().field("action", "restored").field("path", target).text(format!("restored: {}", target)))
+            }
+            ["created", target] => {
+                std::fs::remove_file(target)?;
+                Ok(Record::new().field("action", "removed").field("path", target).text(format!("removed: {}", target)))
+            }
+            _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Invalid journal line: {}", line))),
+        }
+    }
+}
+
+impl common::Command for RestoreCmd {
+    fn create() -> Box<Self> { Box::<Self>::new(Self {}) }
+    fn name() -> &'static str { "restore" }
+    fn fill_subcommand<'a, 'b>(&self, app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+        app.subcommand(clap::App::new(Self::name()))
+    }
+    fn run(&self, args: Option<&clap::ArgMatches>) -> Result<(), Box<dyn std::error::Error>> {
+        let path = journal_path();
+        if !path.exists() {
+            return Err(common::errors::ErrorStr::new("Nothing to restore, run convert with --backup first"));
+        }
+
+        let journal = std::fs::read_to_string(&path)?;
+        let mut failed = 0;
+        let mut out = Output::new(args);
+        // newest changes are rolled back first
+        for line in journal.lines().rev() {
+            let result = Self::restore(line);
+            if result.is_err() {
+                eprintln!("Failed to restore. Error: {}", result.err().unwrap());
+                failed += 1;
+                continue;
+            }
+            out.record(result.unwrap());
+        }
+
+        if failed > 0 {
+            return Err(common::errors::ErrorString::new(format!("Failed to restore {} file(s)", failed)));
+        }
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,173 @@
+use crate::common;
+use crate::common::output::{Output, Record};
+
+use super::backup;
+use super::detect;
+use super::labels;
+use super::encoding::EncodingRef;
+use super::ListFilesConfig;
+
+#[allow(clippy::enum_variant_names)]
+enum EBomAction {
+    EAdd,
+    EStrip,
+    ERewrite,
+}
+
+pub struct BomCmd;
+
+impl BomCmd {
+    fn strip_boms(data: &[u8]) -> (Option<EncodingRef>, &[u8]) {
+        let bom_encoding = detect::detect_bom(data);
+        let mut body = data;
+        while let Some(e) = detect::detect_bom(body) {
+            if e.name() != bom_encoding.unwrap().name() { break; }
+            body = &body[detect::get_bom_mark(&e).len()..];
+        }
+        (bom_encoding, body)
+    }
+    /// Returns the new file content or `None` if the file should stay as is.
+    fn process(data: &[u8], action: &EBomAction, e: EncodingRef) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        let (bom_encoding, body) = Self::strip_boms(data);
+        let bom = detect::get_bom_mark(&e);
+
+        let result = match action {
+            EBomAction::EAdd => {
+                if bom_encoding.is_some() { return Ok(None); }
+                if bom.is_empty() {
+                    return Err(common::errors::ErrorString::new(format!("Encoding {} has no BOM", e.name())));
+                }
+                if e.decode(body, encoding::types::DecoderTrap::Strict).is_err() {
+                    return Err(common::errors::ErrorString::new(format!("Content is not valid {}", e.name())));
+                }
+                [&bom[..], body].concat()
+            }
+            EBomAction::EStrip => {
+                body.to_vec()
+            }
+            EBomAction::ERewrite => {
+                if bom_encoding.is_none() { return Ok(None); }
+                let text = bom_encoding.unwrap().decode(body, encoding::types::DecoderTrap::Strict)?;
+                let body = e.encode(&text, encoding::types::EncoderTrap::Strict)?;
+                [&bom[..], &body[..]].concat()
+            }
+        };
+
+        if result == data { Ok(None) } else { Ok(Some(result)) }
+    }
+    fn describe(action: &EBomAction, data: &[u8], e: EncodingRef) -> String {
+        match action {
+            EBomAction::EAdd => format!("add {} BOM", e.name()),
+            EBomAction::EStrip => format!("strip {} BOM", detect::detect_bom(data).unwrap().name()),
+            EBomAction::ERewrite => format!("rewrite {} BOM to {}", detect::detect_bom(data).unwrap().name(), e.name()),
+        }
+    }
+}
+
+impl common::Command for BomCmd {
+    fn create() -> Box<Self> { Box::<Self>::new(Self {}) }
+    fn name() -> &'static str { "bom" }
+    fn fill_subcommand<'a, 'b>(&self, app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+        app.subcommand(
+            ListFilesConfig::add_selection_args(clap::App::new(Self::name()))
+                .arg(clap::Arg::with_name("path").required(true))
+                .arg(
+                    clap::Arg::with_name("action")
+                        .required(true)
+                        .possible_values(&["add", "strip", "rewrite"])
+                        .help("rewrite converts files with any BOM to the encoding and BOM of --encoding"))
+                .arg(
+                    clap::Arg::with_name("encoding")
+                        .long("encoding")
+                        .takes_value(true)
+                        .default_value("utf-8")
+                        .help("BOM to add or rewrite to: utf-8, utf-16le, utf-16be"))
+                .arg(
+                    clap::Arg::with_name("recursive")
+                        .long("recursive")
+                        .short("r"))
+                .arg(
+                    clap::Arg::with_name("dry_run")
+                        .long("dry_run")
+                        .help("only print files that would be changed"))
+        )
+    }
+    fn run(&self, args: Option<&clap::ArgMatches>) -> Result<(), Box<dyn std::error::Error>> {
+        let args = args.unwrap();
+        let action = match args.value_of("action").unwrap() {
+            "add" => EBomAction::EAdd,
+            "strip" => EBomAction::EStrip,
+            _ => EBomAction::ERewrite,
+        };
+        let e = labels::parse_encoding(args.value_of("encoding").unwrap())?;
+        let dry_run = args.is_present("dry_run");
+
+        let mut cfg = ListFilesConfig::new();
+        cfg.set_folder(args.value_of("path").unwrap());
+        cfg.set_recursive(args.is_present("recursive"));
+        cfg.set_selection(args)?;
+
+        let mut out = Output::new(Some(args));
+        let mut changed = 0;
+        let mut binary = 0;
+        let mut failed = 0;
+        for entry in cfg.create_walker()? {
+            if entry.is_err() {
+                eprintln!("{}", entry.err().unwrap());
+                failed += 1;
+                continue;
+            }
+
+            let entry = entry.unwrap();
+            if cfg.filter(&entry) { continue; }
+
+            let path = entry.path();
+            let data = detect::read_file(path.to_str().unwrap());
+            if data.is_err() {
+                eprintln!("Failed to read file {}. Error: {}", path.display(), data.err().unwrap());
+                failed += 1;
+                continue;
+            }
+
+            let data = data.unwrap();
+            if !detect::is_wide(&e) && detect::is_binary(&data) {
+                out.record(Record::new()
+                    .field("path", path.display().to_string())
+                    .field("status", "binary")
+                    .text(format!("binary: {}", path.display())));
+                binary += 1;
+                continue;
+            }
+
+            let result = Self::process(&data, &action, e);
+            if result.is_err() {
+                eprintln!("Failed to process file {}. Error: {}", path.display(), result.err().unwrap());
+                failed += 1;
+                continue;
+            }
+
+            let result = result.unwrap();
+            if result.is_none() { continue; }
+            changed += 1;
+            let change = Self::describe(&action, &data, e);
+            out.record(Record::new()
+                .field("path", path.display().to_string())
+                .field("status", if dry_run { "would change" } else { "changed" })
+                .field("change", &change)
+                .text(format!("{}{}: {}", if dry_run { "would " } else { "" }, change, path.display())));
+            if dry_run { continue; }
+
+            let write_result = backup::write_file(path, &result.unwrap(), None);
+            if write_result.is_err() {
+                eprintln!("Failed to write file {}. Error: {}", path.display(), write_result.err().unwrap());
+                failed += 1;
+            }
+        }
+
+        out.text(format!("{}: {}, Binary: {}, Failed: {}", if dry_run { "To change" } else { "Changed" }, changed, binary, failed));
+        if failed > 0 {
+            return Err(common::errors::ErrorString::new(format!("Failed to change {} file(s)", failed)));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,136 @@
+use crate::common;
+use crate::common::output::{Output, Record};
+
+use super::detect::{EBomPolicy, EFileCheck};
+use super::policy::{Policy, POLICY_FILE};
+use super::{ListFiles, ListFilesConfig, ListResult};
+
+/// Exit code when some files violate the policy.
+const EXIT_VIOLATIONS: i32 = 1;
+/// Exit code when some files could not be checked at all.
+const EXIT_ERRORS: i32 = 2;
+
+#[derive(Default)]
+struct CheckStats {
+    checked: usize,
+    violations: usize,
+    binary: usize,
+    unmatched: usize,
+    errors: usize,
+}
+
+pub struct CheckCmd;
+
+impl CheckCmd {
+    fn report(stats: &mut CheckStats, out: &mut Output, path: &str, result: &ListResult) {
+        let check = match result {
+            Ok(EFileCheck::EBinary) => {
+                stats.binary += 1;
+                return;
+            }
+            Ok(EFileCheck::ENoRule) => {
+                stats.unmatched += 1;
+                return;
+            }
+            Ok(check) => check,
+            Err(msg) => {
+                stats.errors += 1;
+                eprintln!("{}: error: {}", path, msg);
+                return;
+            }
+        };
+        stats.checked += 1;
+        let reason = check.reason();
+        if reason.is_none() { return; }
+        stats.violations += 1;
+        let reason = reason.unwrap();
+        let record = Record::new().field("path", path);
+        match check {
+            EFileCheck::EInvalid(_, issue) => out.record(record
+                .field("error", &reason)
+                .field("line", issue.line)
+                .field("column", issue.column)
+                .text(format!("{}:{}:{}: error: {}", path, issue.line, issue.column, reason))),
+            _ => out.record(record
+                .field("error", &reason)
+                .text(format!("{}: error: {}", path, reason))),
+        }
+    }
+}
+
+impl common::Command for CheckCmd {
+    fn create() -> Box<Self> { Box::<Self>::new(Self {}) }
+    fn name() -> &'static str { "check" }
+    fn fill_subcommand<'a, 'b>(&self, app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+        app.subcommand(
+            ListFilesConfig::add_selection_args(clap::App::new(Self::name()))
+                .about("fails with exit code 1 if any file is not in the encoding, violates the BOM policy or is binary although a rule or --include selects it, 2 if some files could not be read")
+                .arg(clap::Arg::with_name("folder").required(true))
+                .arg(clap::Arg::with_name("encoding").help("required for files not covered by the policy file"))
+                .arg(
+                    clap::Arg::with_name("recursive")
+                        .long("recursive")
+                        .short("r"))
+                .arg(
+                    clap::Arg::with_name("with_bom")
+                        .long("with_bom")
+                        .short("w")
+                        .conflicts_with("without_bom"))
+                .arg(
+                    clap::Arg::with_name("without_bom")
+                        .long("without_bom"))
+                .arg(
+                    clap::Arg::with_name("policy")
+                        .long("policy")
+                        .takes_value(true)
+                        .help("policy file, by default .wtool-encoding.toml is looked up in the folder and its parents"))
+                .arg(
+                    clap::Arg::with_name("unordered")
+                        .long("unordered")
+                        .help("print violations as soon as they are found instead of sorted by path"))
+        )
+    }
+    fn run(&self, args: Option<&clap::ArgMatches>) -> Result<(), Box<dyn std::error::Error>> {
+        let args = args.unwrap();
+        let folder = args.value_of("folder").unwrap();
+        let encoding = args.value_of("encoding");
+        let policy = Policy::open(args.value_of("policy"), folder)?;
+        if encoding.is_none() && policy.is_none() {
+            return Err(common::errors::ErrorString::new(format!("No encoding given and no {} found", POLICY_FILE)));
+        }
+
+        let mut cfg = ListFilesConfig::new();
+        cfg.set_folder(folder);
+        if let Some(encoding) = encoding { cfg.set_decoder(encoding)?; }
+        cfg.set_policy(policy, encoding.is_none());
+        if args.is_present("with_bom") { cfg.set_bom_policy(EBomPolicy::EWithBom); } else if args.is_present("without_bom") { cfg.set_bom_policy(EBomPolicy::EWithoutBom); }
+        cfg.set_recursive(args.is_present("recursive"));
+        cfg.set_unordered(args.is_present("unordered"));
+        cfg.set_selection(args)?;
+
+        // a binary file the policy or --include names is not skipped silently
+        let process = |entry: &ignore::DirEntry| -> ListResult {
+            match ListFiles::check_file(&cfg, entry) {
+                Ok(EFileCheck::EBinary) => {
+                    let expected = cfg.expected_encoding(entry.path().to_str().unwrap());
+                    Ok(expected.map_or(EFileCheck::EBinary, |e| EFileCheck::EUnexpectedBinary(e.name())))
+                }
+                result => result,
+            }
+        };
+        let mut stats = CheckStats::default();
+        let mut out = Output::new(Some(args));
+        ListFiles::walk_with(&cfg, process, |path, result| Self::report(&mut stats, &mut out, path, result))?;
+        drop(out);
+
+        eprintln!("Checked: {}, Violations: {}, Binary: {}, Without rule: {}, Errors: {}",
+                  stats.checked, stats.violations, stats.binary, stats.unmatched, stats.errors);
+        if stats.errors > 0 {
+            return Err(common::errors::ExitError::new(EXIT_ERRORS, format!("Failed to check {} file(s)", stats.errors)));
+        }
+        if stats.violations > 0 {
+            return Err(common::errors::ExitError::new(EXIT_VIOLATIONS, format!("{} file(s) violate the encoding policy", stats.violations)));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,347 @@
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::sync::OnceLock;
+
+use encoding::types::{ByteWriter, CodecError, Encoding, RawDecoder, RawEncoder, StringWriter};
+
+use crate::common;
+
+use super::encoding::EncodingRef;
+
+/// Environment variable with codepage mapping files, separated like PATH.
+/// A file has `0xNN 0xUUUU` lines as the unicode.org mapping tables, `#` starts a comment.
+/// The codepage is named after the file, `cp1125.txt` is `cp1125` and code page 1125.
+pub(super) const MAPPING_FILES_VAR: &str = "WTOOL_CODEPAGES";
+
+/// Upper halves of the built in DOS codepages, the lower halves are ASCII.
+const IBM437: [u16; 128] = [
+    0x00C7, 0x00FC, 0x00E9, 0x00E2, 0x00E4, 0x00E0, 0x00E5, 0x00E7, 0x00EA, 0x00EB, 0x00E8, 0x00EF, 0x00EE, 0x00EC, 0x00C4, 0x00C5,
+    0x00C9, 0x00E6, 0x00C6, 0x00F4, 0x00F6, 0x00F2, 0x00FB, 0x00F9, 0x00FF, 0x00D6, 0x00DC, 0x00A2, 0x00A3, 0x00A5, 0x20A7, 0x0192,
+    0x00E1, 0x00ED, 0x00F3, 0x00FA, 0x00F1, 0x00D1, 0x00AA, 0x00BA, 0x00BF, 0x2310, 0x00AC, 0x00BD, 0x00BC, 0x00A1, 0x00AB, 0x00BB,
+    0x2591, 0x2592, 0x2593, 0x2502, 0x2524, 0x2561, 0x2562, 0x2556, 0x2555, 0x2563, 0x2551, 0x2557, 0x255D, 0x255C, 0x255B, 0x2510,
+    0x2514, 0x2534, 0x252C, 0x251C, 0x2500, 0x253C, 0x255E, 0x255F, 0x255A, 0x2554, 0x2569, 0x2566, 0x2560, 0x2550, 0x256C, 0x2567,
+    0x2568, 0x2564, 0x2565, 0x2559, 0x2558, 0x2552, 0x2553, 0x256B, 0x256A, 0x2518, 0x250C, 0x2588, 0x2584, 0x258C, 0x2590, 0x2580,
+    0x03B1, 0x00DF, 0x0393, 0x03C0, 0x03A3, 0x03C3, 0x00B5, 0x03C4, 0x03A6, 0x0398, 0x03A9, 0x03B4, 0x221E, 0x03C6, 0x03B5, 0x2229,
+    0x2261, 0x00B1, 0x2265, 0x2264, 0x2320, 0x2321, 0x00F7, 0x2248, 0x00B0, 0x2219, 0x00B7, 0x221A, 0x207F, 0x00B2, 0x25A0, 0x00A0,
+];
+const IBM850: [u16; 128] = [
+    0x00C7, 0x00FC, 0x00E9, 0x00E2, 0x00E4, 0x00E0, 0x00E5, 0x00E7, 0x00EA, 0x00EB, 0x00E8, 0x00EF, 0x00EE, 0x00EC, 0x00C4, 0x00C5,
+    0x00C9, 0x00E6, 0x00C6, 0x00F4, 0x00F6, 0x00F2, 0x00FB, 0x00F9, 0x00FF, 0x00D6, 0x00DC, 0x00F8, 0x00A3, 0x00D8, 0x00D7, 0x0192,
+    0x00E1, 0x00ED, 0x00F3, 0x00FA, 0x00F1, 0x00D1, 0x00AA, 0x00BA, 0x00BF, 0x00AE, 0x00AC, 0x00BD, 0x00BC, 0x00A1, 0x00AB, 0x00BB,
+    0x2591, 0x2592, 0x2593, 0x2502, 0x2524, 0x00C1, 0x00C2, 0x00C0, 0x00A9, 0x2563, 0x2551, 0x2557, 0x255D, 0x00A2, 0x00A5, 0x2510,
+    0x2514, 0x2534, 0x252C, 0x251C, 0x2500, 0x253C, 0x00E3, 0x00C3, 0x255A, 0x2554, 0x2569, 0x2566, 0x2560, 0x2550, 0x256C, 0x00A4,
+    0x00F0, 0x00D0, 0x00CA, 0x00CB, 0x00C8, 0x0131, 0x00CD, 0x00CE, 0x00CF, 0x2518, 0x250C, 0x2588, 0x2584, 0x00A6, 0x00CC, 0x2580,
+    0x00D3, 0x00DF, 0x00D4, 0x00D2, 0x00F5, 0x00D5, 0x00B5, 0x00FE, 0x00DE, 0x00DA, 0x00DB, 0x00D9, 0x00FD, 0x00DD, 0x00AF, 0x00B4,
+    0x00AD, 0x00B1, 0x2017, 0x00BE, 0x00B6, 0x00A7, 0x00F7, 0x00B8, 0x00B0, 0x00A8, 0x00B7, 0x00B9, 0x00B3, 0x00B2, 0x25A0, 0x00A0,
+];
+const IBM852: [u16; 128] = [
+    0x00C7, 0x00FC, 0x00E9, 0x00E2, 0x00E4, 0x016F, 0x0107, 0x00E7, 0x0142, 0x00EB, 0x0150, 0x0151, 0x00EE, 0x0179, 0x00C4, 0x0106,
+    0x00C9, 0x0139, 0x013A, 0x00F4, 0x00F6, 0x013D, 0x013E, 0x015A, 0x015B, 0x00D6, 0x00DC, 0x0164, 0x0165, 0x0141, 0x00D7, 0x010D,
+    0x00E1, 0x00ED, 0x00F3, 0x00FA, 0x0104, 0x0105, 0x017D, 0x017E, 0x0118, 0x0119, 0x00AC, 0x017A, 0x010C, 0x015F, 0x00AB, 0x00BB,
+    0x2591, 0x2592, 0x2593, 0x2502, 0x2524, 0x00C1, 0x00C2, 0x011A, 0x015E, 0x2563, 0x2551, 0x2557, 0x255D, 0x017B, 0x017C, 0x2510,
+    0x2514, 0x2534, 0x252C, 0x251C, 0x2500, 0x253C, 0x0102, 0x0103, 0x255A, 0x2554, 0x2569, 0x2566, 0x2560, 0x2550, 0x256C, 0x00A4,
+    0x0111, 0x0110, 0x010E, 0x00CB, 0x010F, 0x0147, 0x00CD, 0x00CE, 0x011B, 0x2518, 0x250C, 0x2588, 0x2584, 0x0162, 0x016E, 0x2580,
+    0x00D3, 0x00DF, 0x00D4, 0x0143, 0x0144, 0x0148, 0x0160, 0x0161, 0x0154, 0x00DA, 0x0155, 0x0170, 0x00FD, 0x00DD, 0x0163, 0x00B4,
+    0x00AD, 0x02DD, 0x02DB, 0x02C7, 0x02D8, 0x00A7, 0x00F7, 0x00B8, 0x00B0, 0x00A8, 0x02D9, 0x0171, 0x0158, 0x0159, 0x25A0, 0x00A0,
+];
+
+/// Single-byte codepage defined by a table of 256 characters.
+pub(super) struct TableEncoding {
+    name: &'static str,
+    labels: Vec<&'static str>,
+    code_page: Option<usize>,
+    // None for bytes undefined in the codepage
+    forward: [Option<char>; 256],
+    backward: HashMap<char, u8>,
+}
+
+impl TableEncoding {
+    fn new(name: &'static str, labels: Vec<&'static str>, code_page: Option<usize>, forward: [Option<char>; 256]) -> Self {
+        let mut backward = HashMap::new();
+        for (b, c) in forward.iter().enumerate().rev() {
+            // the first byte wins when a character is mapped twice
+            if c.is_some() { backward.insert(c.unwrap(), b as u8); }
+        }
+        Self { name, labels, code_page, forward, backward }
+    }
+    fn ascii_table() -> [Option<char>; 256] {
+        let mut forward = [None; 256];
+        for (b, c) in forward.iter_mut().enumerate().take(0x80) {
+            *c = Some(b as u8 as char);
+        }
+        forward
+    }
+    fn from_upper_half(name: &'static str, labels: Vec<&'static str>, code_page: usize, upper: &[u16; 128]) -> Self {
+        let mut forward = Self::ascii_table();
+        for (i, u) in upper.iter().enumerate() {
+            forward[0x80 + i] = std::char::from_u32(*u as u32);
+        }
+        Self::new(name, labels, Some(code_page), forward)
+    }
+    /// Mac Cyrillic before Mac OS 9, which put Ukrainian letters and the euro sign in place of three symbols.
+    fn mac_cyrillic_classic() -> Self {
+        let mut forward = Self::ascii_table();
+        for b in 0x80..=0xFFu8 {
+            let text = encoding::all::MAC_CYRILLIC.decode(&[b], encoding::types::DecoderTrap::Strict);
+            forward[b as usize] = text.ok().and_then(|t| t.chars().next());
+        }
+        forward[0xA2] = Some('\u{00A2}');
+        forward[0xB6] = Some('\u{2202}');
+        forward[0xFF] = Some('\u{00A4}');
+        Self::new("mac-cyrillic-classic", vec!["x-mac-cyrillic-classic"], Some(10007), forward)
+    }
+    fn parse_number(text: &str) -> Option<u32> {
+        let text = text.trim();
+        if text.starts_with("0x") || text.starts_with("0X") { u32::from_str_radix(&text[2..], 16).ok() } else { text.parse().ok() }
+    }
+    /// Loads a unicode.org style mapping file, bytes missing in the file are undefined.
+    fn load(path: &std::path::Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let stem = path.file_stem().map(|s| s.to_string_lossy().to_lowercase()).unwrap_or_default();
+        if stem.is_empty() {
+            return Err(common::errors::ErrorStr::new("the file name is not a codepage name"));
+        }
+        let mut forward = [None; 256];
+        let reader = std::io::BufReader::new(std::fs::File::open(path)?);
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.split('#').next().unwrap();
+            let mut columns = line.split_whitespace();
+            let byte = columns.next();
+            if byte.is_none() { continue; }
+            let byte = Self::parse_number(byte.unwrap()).filter(|b| *b <= 0xFF);
+            // a byte without a character is explicitly undefined
+            let c = columns.next().map(|c| Self::parse_number(c).and_then(std::char::from_u32));
+            if byte.is_none() || c == Some(None) {
+                return Err(common::errors::ErrorString::new(format!("line {}: expected a byte and a character as 0xNN 0xUUUU", number + 1)));
+            }
+            forward[byte.unwrap() as usize] = c.flatten();
+        }
+
+        let name: &'static str = Box::leak(stem.into_boxed_str());
+        let code_page = Self::parse_number(name.trim_start_matches("cp").trim_start_matches("ibm").trim_start_matches("windows-"));
+        Ok(Self::new(name, vec![name], code_page.map(|cp| cp as usize), forward))
+    }
+    fn is_ascii_compatible(&self) -> bool {
+        self.forward.iter().take(0x80).enumerate().all(|(b, c)| *c == Some(b as u8 as char))
+    }
+}
+
+impl Encoding for TableEncoding {
+    fn name(&self) -> &'static str { self.name }
+    fn raw_encoder(&self) -> Box<dyn RawEncoder> { Box::new(TableEncoder { table: registry().table(self.name) }) }
+    fn raw_decoder(&self) -> Box<dyn RawDecoder> { Box::new(TableDecoder { table: registry().table(self.name) }) }
+}
+
+struct TableEncoder {
+    table: &'static TableEncoding,
+}
+
+impl RawEncoder for TableEncoder {
+    fn from_self(&self) -> Box<dyn RawEncoder> { Box::new(Self { table: self.table }) }
+    fn is_ascii_compatible(&self) -> bool { self.table.is_ascii_compatible() }
+    fn raw_feed(&mut self, input: &str, output: &mut dyn ByteWriter) -> (usize, Option<CodecError>) {
+        output.writer_hint(input.len());
+        for (i, c) in input.char_indices() {
+            let b = self.table.backward.get(&c);
+            if b.is_none() {
+                return (i, Some(CodecError { upto: (i + c.len_utf8()) as isize, cause: "unrepresentable character".into() }));
+            }
+            output.write_byte(*b.unwrap());
+        }
+        (input.len(), None)
+    }
+    fn raw_finish(&mut self, _output: &mut dyn ByteWriter) -> Option<CodecError> {
+        None
+    }
+}
+
+/// UTF-32 in either byte order, the library has no UTF-32 support.
+pub(super) struct Utf32Encoding {
+    name: &'static str,
+    big_endian: bool,
+}
+
+pub(super) static UTF_32LE: Utf32Encoding = Utf32Encoding { name: "utf-32le", big_endian: false };
+pub(super) static UTF_32BE: Utf32Encoding = Utf32Encoding { name: "utf-32be", big_endian: true };
+
+impl Encoding for Utf32Encoding {
+    fn name(&self) -> &'static str { self.name }
+    fn raw_encoder(&self) -> Box<dyn RawEncoder> { Box::new(Utf32Encoder { big_endian: self.big_endian }) }
+    fn raw_decoder(&self) -> Box<dyn RawDecoder> { Box::new(Utf32Decoder { big_endian: self.big_endian, pending: Vec::new() }) }
+}
+
+struct Utf32Encoder {
+    big_endian: bool,
+}
+
+impl RawEncoder for Utf32Encoder {
+    fn from_self(&self) -> Box<dyn RawEncoder> { Box::new(Self { big_endian: self.big_endian }) }
+    fn raw_feed(&mut self, input: &str, output: &mut dyn ByteWriter) -> (usize, Option<CodecError>) {
+        output.writer_hint(input.len() * 4);
+        for c in input.chars() {
+            let bytes = if self.big_endian { (c as u32).to_be_bytes() } else { (c as u32).to_le_bytes() };
+            output.write_bytes(&bytes);
+        }
+        (input.len(), None)
+    }
+    fn raw_finish(&mut self, _output: &mut dyn ByteWriter) -> Option<CodecError> {
+        None
+    }
+}
+
+/// Keeps the bytes of a code unit split between two `raw_feed` calls like the library UTF-16 decoder.
+struct Utf32Decoder {
+    big_endian: bool,
+    pending: Vec<u8>,
+}
+
+impl Utf32Decoder {
+    fn decode_unit(&self, unit: &[u8]) -> Option<char> {
+        let unit = [unit[0], unit[1], unit[2], unit[3]];
+        let code = if self.big_endian { u32::from_be_bytes(unit) } else { u32::from_le_bytes(unit) };
+        std::char::from_u32(code)
+    }
+}
+
+impl RawDecoder for Utf32Decoder {
+    fn from_self(&self) -> Box<dyn RawDecoder> { Box::new(Self { big_endian: self.big_endian, pending: Vec::new() }) }
+    fn raw_feed(&mut self, input: &[u8], output: &mut dyn StringWriter) -> (usize, Option<CodecError>) {
+        output.writer_hint(input.len() / 4);
+        let mut i = 0;
+        if !self.pending.is_empty() {
+            let take = (4 - self.pending.len()).min(input.len());
+            self.pending.extend_from_slice(&input[..take]);
+            i = take;
+            if self.pending.len() < 4 { return (0, None); }
+            let c = self.decode_unit(&self.pending);
+            self.pending.clear();
+            if c.is_none() {
+                return (0, Some(CodecError { upto: i as isize, cause: "invalid sequence".into() }));
+            }
+            output.write_char(c.unwrap());
+        }
+        while i + 4 <= input.len() {
+            let c = self.decode_unit(&input[i..i + 4]);
+            if c.is_none() {
+                return (i, Some(CodecError { upto: i as isize + 4, cause: "invalid sequence".into() }));
+            }
+            output.write_char(c.unwrap());
+            i += 4;
+        }
+        self.pending.extend_from_slice(&input[i..]);
+        (i, None)
+    }
+    fn raw_finish(&mut self, _output: &mut dyn StringWriter) -> Option<CodecError> {
+        if self.pending.is_empty() { return None; }
+        self.pending.clear();
+        Some(CodecError { upto: 0, cause: "incomplete sequence".into() })
+    }
+}
+
+struct TableDecoder {
+    table: &'static TableEncoding,
+}
+
+impl RawDecoder for TableDecoder {
+    fn from_self(&self) -> Box<dyn RawDecoder> { Box::new(Self { table: self.table }) }
+    fn is_ascii_compatible(&self) -> bool { self.table.is_ascii_compatible() }
+    fn raw_feed(&mut self, input: &[u8], output: &mut dyn StringWriter) -> (usize, Option<CodecError>) {
+        output.writer_hint(input.len());
+        for (i, b) in input.iter().enumerate() {
+            let c = self.table.forward[*b as usize];
+            if c.is_none() {
+                return (i, Some(CodecError { upto: i as isize + 1, cause: "invalid sequence".into() }));
+            }
+            output.write_char(c.unwrap());
+        }
+        (input.len(), None)
+    }
+    fn raw_finish(&mut self, _output: &mut dyn StringWriter) -> Option<CodecError> {
+        None
+    }
+}
+
+/// Encoding added to the library ones with its labels and Windows code page.
+struct Codec {
+    encoding: EncodingRef,
+    labels: Vec<&'static str>,
+    code_page: Option<usize>,
+}
+
+/// Library encodings followed by the added ones.
+struct Registry {
+    tables: Vec<&'static TableEncoding>,
+    codecs: Vec<Codec>,
+    all: Vec<EncodingRef>,
+}
+
+impl Registry {
+    fn load() -> Self {
+        let mut registry = Self { tables: Vec::new(), codecs: Vec::new(), all: encoding::all::encodings().to_vec() };
+        registry.add(&UTF_32LE, vec!["utf-32le", "utf-32", "utf32"], Some(12000));
+        registry.add(&UTF_32BE, vec!["utf-32be"], Some(12001));
+        registry.add_table(TableEncoding::from_upper_half("ibm437", vec!["ibm437", "cp437", "csibm437"], 437, &IBM437));
+        registry.add_table(TableEncoding::from_upper_half("ibm850", vec!["ibm850", "cp850", "csibm850"], 850, &IBM850));
+        registry.add_table(TableEncoding::from_upper_half("ibm852", vec!["ibm852", "cp852", "csibm852"], 852, &IBM852));
+        registry.add_table(TableEncoding::mac_cyrillic_classic());
+
+        let paths = std::env::var_os(MAPPING_FILES_VAR).unwrap_or_default();
+        for path in std::env::split_paths(&paths).filter(|p| !p.as_os_str().is_empty()) {
+            let table = TableEncoding::load(&path);
+            if table.is_err() {
+                eprintln!("Skipped codepage mapping file {}: {}", path.display(), table.err().unwrap());
+                continue;
+            }
+            let table = table.unwrap();
+            let is_known = encoding::label::encoding_from_whatwg_label(table.name).is_some()
+                || registry.all.iter().any(|e| e.name() == table.name)
+                || registry.codecs.iter().any(|c| c.labels.contains(&table.name));
+            if is_known {
+                eprintln!("Skipped codepage mapping file {}: encoding {} already exists", path.display(), table.name);
+                continue;
+            }
+            registry.add_table(table);
+        }
+        registry
+    }
+    fn add(&mut self, encoding: EncodingRef, labels: Vec<&'static str>, code_page: Option<usize>) {
+        self.codecs.push(Codec { encoding, labels, code_page });
+        self.all.push(encoding);
+    }
+    fn add_table(&mut self, table: TableEncoding) {
+        let table: &'static TableEncoding = Box::leak(Box::new(table));
+        self.tables.push(table);
+        self.add(table, table.labels.clone(), table.code_page);
+    }
+    fn table(&self, name: &str) -> &'static TableEncoding {
+        self.tables.iter().find(|t| t.name == name).unwrap()
+    }
+}
+
+fn registry() -> &'static Registry {
+    static REGISTRY: OnceLock<Registry> = OnceLock::new();
+    REGISTRY.get_or_init(Registry::load)
+}
+
+/// All encodings known to wtool.
+pub(super) fn encodings() -> &'static [EncodingRef] {
+    &registry().all
+}
+
+/// Added encoding by its name or one of its labels.
+pub(super) fn encoding_from_label(label: &str) -> Option<EncodingRef> {
+    let label = label.trim().to_lowercase();
+    registry().codecs.iter()
+        .find(|c| c.encoding.name() == label || c.labels.contains(&label.as_str()))
+        .map(|c| c.encoding)
+}
+
+pub(super) fn encoding_from_code_page(cp: usize) -> Option<EncodingRef> {
+    registry().codecs.iter().find(|c| c.code_page == Some(cp)).map(|c| c.encoding)
+}
+
+/// Labels and code page of an added encoding, nothing for the library ones.
+pub(super) fn codec_labels(e: &EncodingRef) -> (Vec<&'static str>, Option<usize>) {
+    let codec = registry().codecs.iter().find(|c| c.encoding.name() == e.name());
+    codec.map_or((Vec::new(), None), |c| (c.labels.clone(), c.code_page))
+}
@@ -1,10 +1,16 @@
 use std::io::Read;
 
 use crate::common;
+use crate::common::output::{Output, Record};
+
+use super::codecs;
+use super::detector;
+use super::stream;
+use super::traps::Issue;
 
 use super::encoding::EncodingRef;
 
-fn read_file(path: &str) -> std::io::Result<Vec<u8>> {
+pub(super) fn read_file(path: &str) -> std::io::Result<Vec<u8>> {
     let file = std::fs::OpenOptions::new()
         .read(true)
         .write(false)
@@ -27,89 +33,340 @@ pub enum EBomPolicy {
     EWithoutBom,
 }
 
-fn get_bom_mark(e: &EncodingRef) -> Vec<u8> {
+pub(super) fn get_bom_mark(e: &EncodingRef) -> Vec<u8> {
     match e.name() {
         "utf-8" => { vec![0xEF, 0xBB, 0xBF] }
         "utf-16be" => { vec![0xFE, 0xFF] }
         "utf-16le" => { vec![0xFF, 0xFE] }
+        "utf-32be" => { vec![0x00, 0x00, 0xFE, 0xFF] }
+        "utf-32le" => { vec![0xFF, 0xFE, 0x00, 0x00] }
         _ => { vec![] }
     }
 }
 
-fn is_same_encoding(data: &[u8], e: &EncodingRef, bom_policy: &EBomPolicy) -> bool {
-    match bom_policy {
-        EBomPolicy::EIgnore => {}
-        EBomPolicy::EWithBom => {
-            let bom = get_bom_mark(e);
-            if data.len() < bom.len() { return false; }
-            for i in 0..bom.len() {
-                if data[i] != bom[i] { return false; }
-            }
+pub(super) fn detect_bom(data: &[u8]) -> Option<EncodingRef> {
+    // UTF-32LE goes before UTF-16LE, its BOM starts with the UTF-16LE one
+    let unicode: [EncodingRef; 5] =
+        [encoding::all::UTF_8, &codecs::UTF_32LE, &codecs::UTF_32BE, encoding::all::UTF_16LE, encoding::all::UTF_16BE];
+    for e in unicode.iter() {
+        if data.starts_with(&get_bom_mark(e)[..]) { return Some(*e); }
+    }
+    None
+}
+
+/// Length of the BOM `data` starts with if it is the BOM of `e`, 0 otherwise.
+pub(super) fn bom_len(data: &[u8], e: &EncodingRef) -> usize {
+    if detect_bom(data).is_some_and(|b| b.name() == e.name()) { get_bom_mark(e).len() } else { 0 }
+}
+
+/// Binary detection looks at this many bytes from the start of a file.
+pub(super) const BINARY_SAMPLE_SIZE: usize = 8 * 1024;
+
+/// Text with more control characters than this share is treated as binary.
+const MAX_CONTROL_RATIO: f32 = 0.1;
+
+/// Signatures of common binary formats that may have no NUL bytes in the first block.
+const MAGIC_NUMBERS: [&[u8]; 14] = [
+    b"\x89PNG\r\n\x1a\n",
+    b"\xFF\xD8\xFF",
+    b"GIF87a",
+    b"GIF89a",
+    b"%PDF-",
+    b"PK\x03\x04",
+    b"\x1F\x8B",
+    b"\xFD7zXZ\x00",
+    b"7z\xBC\xAF\x27\x1C",
+    b"Rar!\x1A\x07",
+    b"\x7FELF",
+    b"\xCA\xFE\xBA\xBE",
+    b"\x00asm",
+    b"SQLite format 3\x00",
+];
+
+fn is_control(byte: u8) -> bool {
+    match byte {
+        b'\t' | b'\n' | b'\r' | 0x0C | 0x1B => false,
+        _ => byte < 0x20 || byte == 0x7F,
+    }
+}
+
+/// UTF-16 and UTF-32 text without a BOM is full of NUL bytes, so files read in them skip the binary check.
+pub(super) fn is_wide(e: &EncodingRef) -> bool {
+    e.name().starts_with("utf-16") || e.name().starts_with("utf-32")
+}
+
+/// BOM-less UTF-16 or UTF-32 text has at least this share of ASCII code units, spaces and line breaks at least.
+const MIN_WIDE_ASCII_RATIO: f32 = 0.01;
+
+/// Private-use, surrogate and noncharacter code units are this rare in UTF-16 or UTF-32 text at most.
+const MAX_WIDE_ODD_RATIO: f32 = 0.01;
+
+/// Without a BOM nearly any even-length data decodes as UTF-16, so the content of `len` bytes with the sample
+/// `data` must look like it: whole code units, NUL bytes where ASCII characters have them and few odd units.
+pub(super) fn is_plausible_wide(data: &[u8], len: u64, e: &EncodingRef) -> bool {
+    let width = if e.name().starts_with("utf-32") { 4 } else { 2 };
+    let big_endian = e.name().ends_with("be");
+    if !len.is_multiple_of(width as u64) { return false; }
+
+    let (mut total, mut ascii, mut odd) = (0, 0, 0);
+    for unit in data.chunks_exact(width) {
+        let unit = if big_endian {
+            unit.iter().fold(0u32, |v, b| (v << 8) | *b as u32)
+        } else {
+            unit.iter().rev().fold(0u32, |v, b| (v << 8) | *b as u32)
+        };
+        total += 1;
+        match unit {
+            0..=0x7F => ascii += 1,
+            // a surrogate pair counts as two odd units in UTF-16, it is rare in text anyway
+            0xD800..=0xDFFF | 0xE000..=0xF8FF | 0xFDD0..=0xFDEF | 0xFFFE | 0xFFFF => odd += 1,
+            unit if unit > 0x10FFFF => odd += 1,
+            _ => {}
         }
-        EBomPolicy::EWithoutBom => {
-            let bom = get_bom_mark(e);
-            if data.len() < bom.len() { return false; }
-            let mut has_bom = true;
-            for i in 0..bom.len() {
-                if data[i] != bom[i] {
-                    has_bom = false;
-                    break;
-                }
-            }
-            if has_bom {
-                return false;
-            }
+    }
+    if total == 0 { return true; }
+    ascii as f32 / total as f32 >= MIN_WIDE_ASCII_RATIO && odd as f32 / total as f32 <= MAX_WIDE_ODD_RATIO
+}
+
+/// Guesses by NUL bytes, control characters and magic numbers whether the content is not text.
+pub(super) fn is_binary(data: &[u8]) -> bool {
+    match detect_bom(data) {
+        Some(e) if e.name() != "utf-8" => return false,
+        _ => {}
+    }
+    if MAGIC_NUMBERS.iter().any(|magic| data.starts_with(magic)) { return true; }
+
+    let sample = &data[..data.len().min(BINARY_SAMPLE_SIZE)];
+    if sample.contains(&0) { return true; }
+    let controls = sample.iter().filter(|b| is_control(**b)).count();
+    !sample.is_empty() && controls as f32 / sample.len() as f32 > MAX_CONTROL_RATIO
+}
+
+pub(super) fn is_binary_file(path: &str) -> std::io::Result<bool> {
+    let mut file = std::fs::File::open(path)?;
+    let sample = stream::read_sample(&mut file, BINARY_SAMPLE_SIZE)?;
+    Ok(is_binary(&sample))
+}
+
+/// Outcome of checking a file against an encoding and BOM policy.
+/// Encoding names are the expected ones.
+#[allow(clippy::enum_variant_names)]
+pub(super) enum EFileCheck {
+    EMatch,
+    EBinary,
+    // binary content in a file selected as text of the encoding
+    EUnexpectedBinary(&'static str),
+    // no BOM and the content does not look like text of the UTF-16 or UTF-32 encoding
+    EImplausibleWide(&'static str),
+    EMissingBom(&'static str),
+    EUnexpectedBom(&'static str),
+    // found BOM and expected encoding
+    EBomMismatch(&'static str, &'static str),
+    EInvalid(&'static str, Issue),
+    // found and expected line endings
+    EWrongEol(&'static str, &'static str),
+    // policy file has no rule for the file
+    ENoRule,
+}
+
+impl EFileCheck {
+    /// Why the file violates the policy, `None` if it does not.
+    pub(super) fn reason(&self) -> Option<String> {
+        match self {
+            EFileCheck::EUnexpectedBinary(encoding) => Some(format!("looks like a binary file, expected {} text", encoding)),
+            EFileCheck::EImplausibleWide(encoding) => Some(format!("has no BOM and does not look like {} text", encoding)),
+            EFileCheck::EMissingBom(encoding) => Some(format!("missing {} BOM", encoding)),
+            EFileCheck::EUnexpectedBom(encoding) => Some(format!("unexpected {} BOM", encoding)),
+            EFileCheck::EBomMismatch(found, expected) => Some(format!("{} BOM, expected {}", found, expected)),
+            EFileCheck::EWrongEol(found, expected) => Some(format!("{} line endings, expected {}", found, expected)),
+            EFileCheck::EInvalid(encoding, issue) =>
+                Some(format!("not valid {}, invalid sequence {} at byte {}", encoding, issue.input, issue.offset)),
+            _ => None,
         }
     }
+}
+
+pub(super) fn check_bom_policy(data: &[u8], e: &EncodingRef, bom_policy: &EBomPolicy) -> EFileCheck {
+    let bom = get_bom_mark(e);
+    match bom_policy {
+        EBomPolicy::EWithBom if !data.starts_with(&bom[..]) => EFileCheck::EMissingBom(e.name()),
+        EBomPolicy::EWithoutBom if !bom.is_empty() && data.starts_with(&bom[..]) => EFileCheck::EUnexpectedBom(e.name()),
+        _ => EFileCheck::EMatch,
+    }
+}
 
-    let trap = encoding::types::DecoderTrap::Strict;
-    let (result, _) =
-        encoding::types::decode(&data, trap, *e);
-    return result.is_ok();
+/// Decodes everything the reader returns chunk by chunk and stops after `limit` invalid sequences.
+pub(super) fn find_invalid_sequences(reader: &mut dyn Read, e: &EncodingRef, limit: usize) -> std::io::Result<Vec<Issue>> {
+    let mut decoder = stream::StreamDecoder::new(*e, encoding::types::DecoderTrap::Replace, 0);
+    let mut chunk = vec![0u8; stream::CHUNK_SIZE];
+    let mut text = String::new();
+    let mut issues = Vec::new();
+    loop {
+        let size = reader.read(&mut chunk)?;
+        if size == 0 { break; }
+        // the replace trap never fails
+        let _ = decoder.feed(&chunk[..size], &mut text, &mut issues);
+        if issues.len() >= limit {
+            issues.truncate(limit);
+            return Ok(issues);
+        }
+        text.clear();
+    }
+    let _ = decoder.finish(&mut text, &mut issues);
+    issues.truncate(limit);
+    Ok(issues)
 }
 
-pub fn is_file_has_same_encoding(filepath: &str, e: &EncodingRef, bom_policy: &EBomPolicy) -> std::io::Result<bool> {
-    let file_content = read_file(filepath);
-    if file_content.is_err() {
-        return Err(file_content.err().unwrap());
+/// Decodes everything the reader returns chunk by chunk and calls `handle` with every line and its number.
+/// Line endings are not passed, invalid sequences are replaced.
+pub(super) fn for_each_line(reader: &mut dyn Read, e: &EncodingRef, handle: &mut dyn FnMut(usize, &str)) -> std::io::Result<()> {
+    let mut decoder = stream::StreamDecoder::new(*e, encoding::types::DecoderTrap::Replace, 0);
+    let mut chunk = vec![0u8; stream::CHUNK_SIZE];
+    let mut text = String::new();
+    let mut issues = Vec::new();
+    let mut number = 1;
+    loop {
+        let size = reader.read(&mut chunk)?;
+        if size == 0 { break; }
+        // the replace trap never fails
+        let _ = decoder.feed(&chunk[..size], &mut text, &mut issues);
+        issues.clear();
+        // the last line may continue in the next chunk
+        let complete = text.rfind('\n').map_or(0, |i| i + 1);
+        for line in text[..complete].lines() {
+            handle(number, line);
+            number += 1;
+        }
+        text.drain(..complete);
+    }
+    let _ = decoder.finish(&mut text, &mut issues);
+    for line in text.lines() {
+        handle(number, line);
+        number += 1;
     }
-    let file_content = file_content.unwrap();
-    Ok(is_same_encoding(&file_content[..], e, bom_policy))
+    Ok(())
 }
 
+/// With `trust_bom` a file with a BOM is in the encoding of the BOM and is not decoded,
+/// otherwise a BOM of another encoding is reported only if the file decodes without errors.
+pub(super) fn check_file_encoding(filepath: &str, e: &EncodingRef, bom_policy: &EBomPolicy, trust_bom: bool) -> std::io::Result<EFileCheck> {
+    let mut file = std::fs::File::open(filepath)?;
+    let len = file.metadata()?.len();
+    let head = stream::read_sample(&mut file, BINARY_SAMPLE_SIZE)?;
+    if !is_wide(e) && is_binary(&head) { return Ok(EFileCheck::EBinary); }
+    let bom = detect_bom(&head);
+    let bom_mismatch = bom.filter(|b| b.name() != e.name()).map(|b| EFileCheck::EBomMismatch(b.name(), e.name()));
+    if trust_bom {
+        if let Some(mismatch) = bom_mismatch { return Ok(mismatch); }
+    }
+    match check_bom_policy(&head, e, bom_policy) {
+        EFileCheck::EMatch => {}
+        violation => return Ok(violation),
+    }
+    if trust_bom && bom.is_some() { return Ok(EFileCheck::EMatch); }
+    // an invalid sequence is the more precise reason
+    let implausible = is_wide(e) && bom.is_none() && !is_plausible_wide(&head, len, e);
+    let mut invalid = find_invalid_sequences(&mut std::io::Cursor::new(head).chain(file), e, 1)?;
+    if !invalid.is_empty() { return Ok(EFileCheck::EInvalid(e.name(), invalid.remove(0))); }
+    if implausible { return Ok(EFileCheck::EImplausibleWide(e.name())); }
+    Ok(bom_mismatch.unwrap_or(EFileCheck::EMatch))
+}
 
 pub struct DetectEncodingCmd;
 
+impl DetectEncodingCmd {
+    fn preview(text: &str) -> String {
+        let line = text.lines().next().unwrap_or("");
+        line.chars().take(60).collect()
+    }
+}
+
 impl common::Command for DetectEncodingCmd {
     fn create() -> Box<DetectEncodingCmd> { Box::<_>::new(DetectEncodingCmd {}) }
     fn name() -> &'static str { "detect" }
     fn fill_subcommand<'a, 'b>(&self, app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
         let sub_cmd =
             clap::App::new(Self::name())
-                .arg(clap::Arg::with_name("filepath").required(true));
+                .arg(clap::Arg::with_name("filepath").required(true).help("file to detect, - for stdin"))
+                .arg(
+                    clap::Arg::with_name("top")
+                        .long("top")
+                        .short("n")
+                        .takes_value(true)
+                        .default_value("5")
+                        .help("number of candidates to print"));
         app.subcommand(sub_cmd)
     }
     fn run(&self, args: Option<&clap::ArgMatches>) -> Result<(), Box<dyn std::error::Error>> {
         let args = args.unwrap();
         let filepath = args.value_of("filepath").unwrap();
-        let file_data = read_file(filepath)?;
-        let encodings = encoding::all::encodings();
-        let trap = encoding::types::DecoderTrap::Strict;
-        for en in encodings {
-            let fail_back_encoder = *en;
-
-            let (result, encoder_ref) =
-                encoding::types::decode(&file_data[..], trap, fail_back_encoder);
-
-            if result.is_ok() {
-                let file_content = result.unwrap();
-                let first_line = file_content.lines().next();
-                if first_line.is_some() {
-                    let line = first_line.unwrap();
-                    println!("{}: {}", en.name(), if line.len() < 60 { line } else { line[0..60].as_ref() });
-                }
-            }
+        let top = args.value_of("top").unwrap().parse::<usize>()?;
+        let (mut file, _) = stream::open_input(filepath)?;
+        let file_data = stream::read_sample(&mut file, detector::SAMPLE_SIZE)?;
+        let mut out = Output::new(Some(args));
+        if is_binary(&file_data) {
+            out.text("Binary file, no text encoding detected");
+            return Ok(());
+        }
+
+        let ranked = detector::detect_encoding(&file_data[..], file_data.len() < detector::SAMPLE_SIZE);
+        if ranked.is_empty() {
+            return Err(common::errors::ErrorStr::new("No encoding can decode the file"));
+        }
+        let ambiguous = detector::is_ambiguous(&ranked);
+        for d in ranked.iter().take(top) {
+            let text = d.encoding.decode(&file_data[d.bom_len..], encoding::types::DecoderTrap::Replace)?;
+            let preview = Self::preview(&text);
+            out.record(Record::new()
+                .field("encoding", d.encoding.name())
+                .field("confidence", d.confidence)
+                .field("bom", d.bom_len > 0)
+                .field("ambiguous", ambiguous)
+                .field("preview", &preview)
+                .text(format!("{:>5.1}% {:<16} {}", d.confidence * 100.0, d.encoding.name(), preview)));
+        }
+        if ambiguous {
+            out.text("Result is ambiguous");
         }
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use encoding::types::{EncoderTrap, EncodingRef};
+
+    use super::super::codecs;
+    use super::is_plausible_wide;
+
+    fn plausible(text: &str, encode_as: EncodingRef, read_as: EncodingRef) -> bool {
+        let data = encode_as.encode(text, EncoderTrap::Strict).unwrap();
+        is_plausible_wide(&data, data.len() as u64, &read_as)
+    }
+
+    #[test]
+    fn wide_text_without_bom_is_plausible() {
+        let text = "Hello, мир!\nSecond line.\n";
+        assert!(plausible(text, encoding::all::UTF_16LE, encoding::all::UTF_16LE));
+        assert!(plausible(text, encoding::all::UTF_16BE, encoding::all::UTF_16BE));
+        assert!(plausible(text, &codecs::UTF_32LE, &codecs::UTF_32LE));
+        assert!(plausible("你好世界，今天天气很好。\n第二行。\n", encoding::all::UTF_16LE, encoding::all::UTF_16LE));
+    }
+
+    #[test]
+    fn single_byte_text_is_not_plausible_wide() {
+        // even lengths, so the strict UTF-16 decode accepts them
+        let french = "Le château se trouve près de la rivière. Les élèves étudient le français.\n";
+        let russian = "Москва является столицей России. Многие люди приезжают сюда.\n";
+        assert!(!plausible(french, encoding::all::WINDOWS_1252, encoding::all::UTF_16LE));
+        assert!(!plausible(russian, encoding::all::IBM866, encoding::all::UTF_16LE));
+        assert!(!plausible(russian, encoding::all::WINDOWS_1251, encoding::all::UTF_16BE));
+    }
+
+    #[test]
+    fn partial_code_unit_is_not_plausible_wide() {
+        let e: EncodingRef = encoding::all::UTF_16LE;
+        let data = e.encode("Hello\n", EncoderTrap::Strict).unwrap();
+        assert!(!is_plausible_wide(&data[..data.len() - 1], data.len() as u64 - 1, &e));
+    }
+}
@@ -0,0 +1,315 @@
+use super::codecs;
+use super::detect;
+use super::encoding::EncodingRef;
+
+/// Encoding guess for a file content, `bom_len` bytes at the start belong to the BOM.
+pub struct Detection {
+    pub encoding: EncodingRef,
+    pub bom_len: usize,
+    pub confidence: f32,
+}
+
+/// Detection looks at this many bytes from the start of a file.
+pub const SAMPLE_SIZE: usize = 1024 * 1024;
+
+/// Candidates closer than this are treated as equally likely.
+const AMBIGUITY_MARGIN: f32 = 0.05;
+
+/// Valid UTF-8 with non-ASCII characters is almost never an accident.
+const UTF8_CONFIDENCE: f32 = 0.99;
+
+/// Tried first, so they win ties against exotic codepages with the same text.
+const PREFERRED: [&str; 6] = ["utf-8", "windows-1251", "windows-1252", "ibm866", "koi8-r", "windows-1250"];
+
+/// Common codepage of a script and rarer ones which decode most of its text the same way.
+/// A near tie between them goes to the common codepage and does not make the result ambiguous.
+const COMMON: [(&str, &[&str]); 2] = [
+    ("windows-1251", &["mac-cyrillic"]),
+    ("windows-1252", &["windows-1250"]),
+];
+
+/// Letter and bigram statistics of a script, letters are lowercase and ordered by frequency.
+struct ScriptModel {
+    letters: &'static str,
+    bigrams: &'static [&'static str],
+}
+
+const MODELS: [ScriptModel; 7] = [
+    // Cyrillic, Russian and Ukrainian
+    ScriptModel {
+        letters: "оеаинтсрвлкмдпуяыьгзбчйхжшюцщэфъёіїє",
+        bigrams: &["ст", "но", "то", "на", "ен", "ов", "ни", "ра", "во", "ко", "ро", "ал", "пр", "по", "ре",
+            "ер", "од", "ли", "ет", "ор", "ел", "ны", "не", "ат", "ан", "ть", "ос", "ом", "ва", "ка", "ой",
+            "ии", "ия", "ля", "ый", "ая", "ие", "ме", "ло", "ри", "де", "ес", "ве", "ин", "ол"],
+    },
+    // Greek
+    ScriptModel {
+        letters: "αοετινσςρκπμλυηδγωχθφβξζψ",
+        bigrams: &["το", "αι", "ου", "ει", "να", "ντ", "τα", "ης", "ια", "ον", "ος", "πο", "κα", "ερ", "ατ"],
+    },
+    // Western European accented letters
+    ScriptModel {
+        letters: "éèàçüöäñßêáíóúâôûëïìòùãõåæøœÿîð",
+        bigrams: &["ée", "és", "èr", "ça", "à ", "êt", "ôt", "ür", "ün", "üb", "är", "ös", "ße", "ñ", "ón",
+            "ía", "ão", "çã", "ué", "ré", "té", "dé"],
+    },
+    // Central European accented letters
+    ScriptModel {
+        letters: "áéíóúýčďěňřšťžůąćęłńśźżőűăâîșțşţğıľ",
+        bigrams: &["ře", "ní", "št", "ě", "ą", "ę", "ł", "ść", "ż", "ó", "ő", "ű"],
+    },
+    // Simplified Chinese
+    ScriptModel {
+        letters: "的一是不了在人有我他这个们中来上大为和国地到以说时要就出会可也你对生能而子那得于着下自之年过发后作里用道行所然家种事成方多经么去法学如都同现当没动面起看定天分还进好小部其些主样理心她本前开",
+        bigrams: &["我们", "中国", "一个", "没有", "什么", "这个", "他们", "可以", "自己", "时候"],
+    },
+    // Traditional Chinese
+    ScriptModel {
+        letters: "的一是不了在人有我他這個們中來上大為和國地到以說時要就出會可也你對生能而子那得於著下自之年過發後作裡用道行所然家種事成方多經麼去法學如都同現當沒動面起看定天分還進好小部其些主樣理心她本前開",
+        bigrams: &["我們", "中國", "一個", "沒有", "什麼", "這個", "他們", "可以", "自己", "時候"],
+    },
+    // Japanese kana and Korean hangul
+    ScriptModel {
+        letters: "のにはをたがでてとしれさいかなるもすまくうっらりんあおこえきつけよやわせどだ이다는의에가를고하지서한기로사",
+        bigrams: &["して", "ます", "です", "した", "ない", "った", "こと", "에서", "하는", "니다", "하고", "있다"],
+    },
+];
+
+fn is_detect_candidate(e: &EncodingRef) -> bool {
+    match e.name() {
+        "error" | "ascii" | "utf-16le" | "utf-16be" | "utf-32le" | "utf-32be" | "hz" | "iso-2022-jp"
+        | "pua-mapped-binary" | "encoder-only-utf-8" => false,
+        // differs from mac-cyrillic in three bytes, so it would always tie with it
+        "mac-cyrillic-classic" => false,
+        _ => true
+    }
+}
+
+/// Encodings tried by detection, the preferred ones first.
+pub fn candidates() -> Vec<EncodingRef> {
+    let mut result = Vec::<EncodingRef>::new();
+    for name in PREFERRED.iter() {
+        result.push(encoding::label::encoding_from_whatwg_label(name).unwrap());
+    }
+    for e in codecs::encodings() {
+        if !is_detect_candidate(e) { continue; }
+        if PREFERRED.contains(&e.name()) { continue; }
+        result.push(*e);
+    }
+    result
+}
+
+fn is_rare_alternative(common: &str, other: &str) -> bool {
+    COMMON.iter().any(|(name, rare)| *name == common && rare.contains(&other))
+}
+
+fn to_lower(c: char) -> char {
+    c.to_lowercase().next().unwrap_or(c)
+}
+
+/// Latin, Greek and Cyrillic have small alphabets, a letter of them which no model knows is a wrong decode.
+fn is_modelled_script(c: char) -> bool {
+    matches!(c as u32, 0xC0..=0x24F | 0x370..=0x3FF | 0x400..=0x52F)
+}
+
+fn letter_weight(c: char) -> f32 {
+    let lower = to_lower(c);
+    if MODELS.iter().any(|m| m.letters.contains(lower)) {
+        1.0
+    } else if is_modelled_script(c) {
+        -1.0
+    } else {
+        0.5
+    }
+}
+
+fn is_latin_letter(c: char) -> bool {
+    ('\u{00C0}'..='\u{024F}').contains(&c) && c.is_alphabetic()
+}
+
+fn is_box_drawing(c: char) -> bool {
+    ('\u{2500}'..='\u{259F}').contains(&c)
+}
+
+/// Letter of a non-Latin script glued to an ASCII letter, e.g. "Grяe".
+fn is_mixed_script(a: char, b: char) -> bool {
+    let is_foreign = |c: char| !c.is_ascii() && c.is_alphabetic() && !is_latin_letter(c);
+    (a.is_ascii_alphabetic() && is_foreign(b)) || (is_foreign(a) && b.is_ascii_alphabetic())
+}
+
+/// Rates how much single non-ASCII characters look like letters of a natural language, from 0 to 1.
+fn score_letters(text: &str) -> f32 {
+    let mut total = 0;
+    let mut good = 0.0;
+    let mut prev = ' ';
+    let mut latin_run = 0;
+    for c in text.chars() {
+        if is_mixed_script(prev, c) { good -= 1.0; }
+        latin_run = if is_latin_letter(c) { latin_run + 1 } else { 0 };
+        if !c.is_ascii() {
+            total += 1;
+            if c.is_alphabetic() {
+                good += letter_weight(c);
+            } else if c.is_control() || is_box_drawing(c) {
+                good -= 1.0;
+            }
+            if latin_run > 2 { good -= 1.0; }
+        }
+        // an uppercase letter inside a lowercase word, e.g. "чтенИя"
+        if prev.is_lowercase() && c.is_uppercase() && !(prev.is_ascii() && c.is_ascii()) { good -= 1.0; }
+        prev = c;
+    }
+    if total == 0 { return 1.0; }
+    if good <= 0.0 { 0.0 } else { good / total as f32 }
+}
+
+/// Share of character pairs with a non-ASCII character that are common bigrams, from 0 to 1.
+fn score_bigrams(text: &str) -> f32 {
+    let mut total = 0;
+    let mut found = 0;
+    let mut pair = String::with_capacity(8);
+    let mut prev = ' ';
+    for c in text.chars() {
+        if !prev.is_ascii() || !c.is_ascii() {
+            pair.clear();
+            pair.push(to_lower(prev));
+            pair.push(to_lower(c));
+            total += 1;
+            if MODELS.iter().any(|m| m.bigrams.iter().any(|b| pair.contains(b))) { found += 1; }
+        }
+        prev = c;
+    }
+    if total == 0 { return 1.0; }
+    found as f32 / total as f32
+}
+
+/// Rates how much the decoded text looks like natural language, from 0 to 1.
+pub fn score_text(text: &str) -> f32 {
+    let letters = score_letters(text);
+    // frequent bigrams make up a quarter to a half of a real text
+    let bigrams = (score_bigrams(text) * 3.0).min(1.0);
+    letters * (0.7 + 0.3 * bigrams)
+}
+
+fn hash_text(text: &str) -> u64 {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    text.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Strictly decodes the data, an incomplete sequence at the end of a partial sample is not an error.
+fn decode_sample(e: EncodingRef, data: &[u8], complete: bool) -> Option<String> {
+    if complete {
+        return e.decode(data, encoding::types::DecoderTrap::Strict).ok();
+    }
+    let mut decoder = e.raw_decoder();
+    let mut text = String::new();
+    let (_, err) = decoder.raw_feed(data, &mut text);
+    if err.is_some() { None } else { Some(text) }
+}
+
+/// Returns candidate encodings ordered from the most to the least likely.
+/// Candidates that decode the data to the same text as a better one are dropped.
+/// `complete` is false when the data is only a sample from the start of a file.
+pub fn detect_encoding(data: &[u8], complete: bool) -> Vec<Detection> {
+    if let Some(e) = detect::detect_bom(data) {
+        return vec![Detection { encoding: e, bom_len: detect::get_bom_mark(&e).len(), confidence: 1.0 }];
+    }
+
+    let mut candidates_found = Vec::<(Detection, u64)>::new();
+    for e in candidates() {
+        let text = decode_sample(e, data, complete);
+        if text.is_none() { continue; }
+        let text = text.unwrap();
+        let mut confidence = score_text(&text);
+        if e.name() == "utf-8" { confidence = confidence.max(UTF8_CONFIDENCE); }
+        candidates_found.push((Detection { encoding: e, bom_len: 0, confidence }, hash_text(&text)));
+    }
+    candidates_found.sort_by(|a, b| b.0.confidence.partial_cmp(&a.0.confidence).unwrap());
+
+    let mut result = Vec::<Detection>::new();
+    let mut texts = std::collections::HashSet::<u64>::new();
+    for (detection, text_hash) in candidates_found {
+        if !texts.insert(text_hash) { continue; }
+        result.push(detection);
+    }
+
+    // a near tie goes to the common codepage of the script
+    let common = result.iter().position(|d| {
+        is_rare_alternative(d.encoding.name(), result[0].encoding.name())
+            && result[0].confidence - d.confidence < AMBIGUITY_MARGIN
+    });
+    if let Some(common) = common {
+        let detection = result.remove(common);
+        result.insert(0, detection);
+    }
+    result
+}
+
+/// Leading candidates that are not clearly worse than the best one, rarer alternatives of the best one are left out.
+pub fn top_candidates(ranked: &[Detection]) -> Vec<&Detection> {
+    ranked.iter()
+        .take_while(|d| (ranked[0].confidence - d.confidence).abs() < AMBIGUITY_MARGIN)
+        .filter(|d| !is_rare_alternative(ranked[0].encoding.name(), d.encoding.name()))
+        .collect()
+}
+
+/// True when the best candidate is not clearly better than the next one.
+pub fn is_ambiguous(ranked: &[Detection]) -> bool {
+    top_candidates(ranked).len() > 1
+}
+
+#[cfg(test)]
+mod tests {
+    use encoding::types::EncoderTrap;
+
+    use super::{detect_encoding, is_ambiguous};
+    use super::super::encoding::EncodingRef;
+
+    const RUSSIAN_C: &str = "/* Модуль чтения конфигурации. Максимальный размер буфера задаётся ниже. */\n\
+        #include <stdio.h>\n\
+        // Мы открываем файл и читаем его построчно\n\
+        int main(void) {\n    printf(\"Привет, мир\\n\"); // вывод приветствия\n    return 0;\n}\n";
+
+    const RUSSIAN: &str = "Москва является столицей России. Многие люди приезжают сюда, чтобы увидеть \
+        Красную площадь, посетить музеи и театры. Метро в Москве считается одним из самых красивых в мире.\n";
+
+    const FRENCH: &str = "Le château se trouve près de la rivière. Les élèves étudient le français à l'école \
+        où ça commence très tôt. Noël approche et la fenêtre est décorée.\n";
+
+    fn detect(e: EncodingRef, text: &str) -> &'static str {
+        let data = e.encode(text, EncoderTrap::Strict).unwrap();
+        let ranked = detect_encoding(&data, true);
+        assert!(!is_ambiguous(&ranked), "ambiguous detection of {}", e.name());
+        ranked[0].encoding.name()
+    }
+
+    #[test]
+    fn detects_cp1251_source() {
+        assert_eq!(detect(encoding::all::WINDOWS_1251, RUSSIAN_C), "windows-1251");
+        assert_eq!(detect(encoding::all::WINDOWS_1251, RUSSIAN), "windows-1251");
+    }
+
+    #[test]
+    fn detects_cp866() {
+        assert_eq!(detect(encoding::all::IBM866, RUSSIAN), "ibm866");
+    }
+
+    #[test]
+    fn detects_koi8_r() {
+        assert_eq!(detect(encoding::all::KOI8_R, RUSSIAN), "koi8-r");
+    }
+
+    #[test]
+    fn detects_cp1252() {
+        assert_eq!(detect(encoding::all::WINDOWS_1252, FRENCH), "windows-1252");
+        assert_eq!(detect(encoding::all::WINDOWS_1252, "Très bien, à bientôt. Où est la pièce?\n"), "windows-1252");
+    }
+
+    #[test]
+    fn detects_utf8() {
+        assert_eq!(detect(encoding::all::UTF_8, RUSSIAN), "utf-8");
+        assert_eq!(detect(encoding::all::UTF_8, FRENCH), "utf-8");
+    }
+}
@@ -0,0 +1,309 @@
+use std::io::Read;
+
+use crate::common;
+use crate::common::output::{Output, Record};
+
+use super::backup;
+use super::detect;
+use super::encoding::EncodingRef;
+use super::labels;
+use super::stream;
+use super::ListFilesConfig;
+
+const CR: u32 = 0x0D;
+const LF: u32 = 0x0A;
+
+pub(super) enum EEol {
+    ECrLf,
+    ELf,
+}
+
+impl EEol {
+    pub(super) fn name(&self) -> &'static str {
+        match self {
+            EEol::ECrLf => "crlf",
+            EEol::ELf => "lf",
+        }
+    }
+}
+
+#[derive(Default)]
+struct EolStats {
+    crlf: usize,
+    lf: usize,
+    cr: usize,
+}
+
+impl EolStats {
+    fn add(&mut self, other: &EolStats) {
+        self.crlf += other.crlf;
+        self.lf += other.lf;
+        self.cr += other.cr;
+    }
+    fn style(&self) -> &'static str {
+        match (self.crlf > 0, self.lf > 0, self.cr > 0) {
+            (false, false, false) => "none",
+            (true, false, false) => "crlf",
+            (false, true, false) => "lf",
+            (false, false, true) => "cr",
+            _ => "mixed",
+        }
+    }
+}
+
+/// Line ending scanner working on code units, so UTF-16 and UTF-32 files are handled as well as single byte ones.
+struct Units<'a> {
+    data: &'a [u8],
+    width: usize,
+    big_endian: bool,
+    offset: usize,
+}
+
+impl<'a> Units<'a> {
+    /// Without a BOM the code units are those of `e`, single bytes if it is not given.
+    fn new(data: &'a [u8], e: Option<EncodingRef>) -> Self {
+        let bom = detect::detect_bom(data);
+        let (width, big_endian) = Self::layout(bom.or(e).map_or("", |e| e.name()));
+        let offset = match bom { Some(b) if width > 1 => detect::get_bom_mark(&b).len(), _ => 0 };
+        Self { data, width, big_endian, offset }
+    }
+    /// Code unit width and byte order of an encoding.
+    fn layout(name: &str) -> (usize, bool) {
+        match name {
+            "utf-16le" => (2, false),
+            "utf-16be" => (2, true),
+            "utf-32le" => (4, false),
+            "utf-32be" => (4, true),
+            _ => (1, false),
+        }
+    }
+    fn len(&self) -> usize {
+        (self.data.len() - self.offset) / self.width
+    }
+    fn get(&self, i: usize) -> u32 {
+        let pos = self.offset + i * self.width;
+        let bytes = &self.data[pos..pos + self.width];
+        if self.big_endian {
+            bytes.iter().fold(0, |unit, b| (unit << 8) | *b as u32)
+        } else {
+            bytes.iter().rev().fold(0, |unit, b| (unit << 8) | *b as u32)
+        }
+    }
+    fn unit_bytes(&self, unit: u32) -> Vec<u8> {
+        let mut bytes: Vec<u8> = (0..self.width).map(|i| (unit >> (8 * i)) as u8).collect();
+        if self.big_endian { bytes.reverse(); }
+        bytes
+    }
+    fn stats(&self) -> EolStats {
+        let mut stats = EolStats::default();
+        let mut i = 0;
+        while i < self.len() {
+            match self.get(i) {
+                CR if i + 1 < self.len() && self.get(i + 1) == LF => {
+                    stats.crlf += 1;
+                    i += 1;
+                }
+                CR => stats.cr += 1,
+                LF => stats.lf += 1,
+                _ => {}
+            }
+            i += 1;
+        }
+        stats
+    }
+    fn convert(&self, eol: &EEol) -> Vec<u8> {
+        let mut eol_bytes = Vec::<u8>::new();
+        if let EEol::ECrLf = eol { eol_bytes.extend(self.unit_bytes(CR)); }
+        eol_bytes.extend(self.unit_bytes(LF));
+
+        let mut result = Vec::<u8>::with_capacity(self.data.len());
+        result.extend_from_slice(&self.data[..self.offset]);
+        let mut i = 0;
+        while i < self.len() {
+            let pos = self.offset + i * self.width;
+            match self.get(i) {
+                CR => {
+                    if i + 1 < self.len() && self.get(i + 1) == LF { i += 1; }
+                    result.extend_from_slice(&eol_bytes);
+                }
+                LF => result.extend_from_slice(&eol_bytes),
+                _ => result.extend_from_slice(&self.data[pos..pos + self.width]),
+            }
+            i += 1;
+        }
+        // trailing bytes of a broken UTF-16 or UTF-32 file
+        result.extend_from_slice(&self.data[self.offset + self.len() * self.width..]);
+        result
+    }
+}
+
+/// Line ending style of a text file content: none, crlf, lf, cr or mixed.
+pub(super) fn line_ending_style(data: &[u8]) -> &'static str {
+    Units::new(data, None).stats().style()
+}
+
+/// Same as `line_ending_style` for a whole file read in chunks. Without a BOM the code units are those of `e`.
+pub(super) fn file_line_ending_style(path: &str, e: &EncodingRef) -> std::io::Result<&'static str> {
+    let mut file = std::fs::File::open(path)?;
+    let mut data = stream::read_sample(&mut file, 4)?;
+    let bom = detect::detect_bom(&data);
+    let (width, big_endian) = Units::layout(bom.map_or(e.name(), |b| b.name()));
+    if let (true, Some(b)) = (width > 1, bom) { data.drain(..detect::get_bom_mark(&b).len()); }
+
+    let mut stats = EolStats::default();
+    let mut chunk = vec![0u8; stream::CHUNK_SIZE];
+    loop {
+        let size = file.read(&mut chunk)?;
+        data.extend_from_slice(&chunk[..size]);
+        let units = Units { data: &data, width, big_endian, offset: 0 };
+        // a partial code unit or a CR which may start a CRLF waits for the next chunk
+        let mut count = units.len();
+        if size > 0 && count > 0 && units.get(count - 1) == CR { count -= 1; }
+        stats.add(&Units { data: &data[..count * width], width, big_endian, offset: 0 }.stats());
+        data.drain(..count * width);
+        if size == 0 { break; }
+    }
+    Ok(stats.style())
+}
+
+/// Rewrites line endings of decoded text fed in pieces, a CR at the end of a piece waits for the next one.
+pub(super) struct EolConverter {
+    eol: &'static str,
+    pending_cr: bool,
+}
+
+impl EolConverter {
+    pub(super) fn new(eol: &EEol) -> Self {
+        Self { eol: if let EEol::ECrLf = eol { "\r\n" } else { "\n" }, pending_cr: false }
+    }
+    pub(super) fn convert(&mut self, text: &str, output: &mut String) {
+        for c in text.chars() {
+            if self.pending_cr {
+                self.pending_cr = false;
+                output.push_str(self.eol);
+                if c == '\n' { continue; }
+            }
+            match c {
+                '\r' => self.pending_cr = true,
+                '\n' => output.push_str(self.eol),
+                _ => output.push(c),
+            }
+        }
+    }
+    pub(super) fn finish(&mut self, output: &mut String) {
+        if self.pending_cr { output.push_str(self.eol); }
+        self.pending_cr = false;
+    }
+}
+
+pub struct EolCmd;
+
+impl common::Command for EolCmd {
+    fn create() -> Box<Self> { Box::<Self>::new(Self {}) }
+    fn name() -> &'static str { "eol" }
+    fn fill_subcommand<'a, 'b>(&self, app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+        app.subcommand(
+            ListFilesConfig::add_selection_args(clap::App::new(Self::name()))
+                .arg(clap::Arg::with_name("path").required(true))
+                .arg(
+                    clap::Arg::with_name("convert")
+                        .long("convert")
+                        .takes_value(true)
+                        .possible_values(&["crlf", "lf"])
+                        .help("rewrite line endings, otherwise only report them"))
+                .arg(
+                    clap::Arg::with_name("encoding")
+                        .long("encoding")
+                        .takes_value(true)
+                        .help("encoding of files without a BOM, needed for UTF-16 and UTF-32 files"))
+                .arg(
+                    clap::Arg::with_name("recursive")
+                        .long("recursive")
+                        .short("r"))
+                .arg(
+                    clap::Arg::with_name("dry_run")
+                        .long("dry_run")
+                        .help("only print files that would be changed"))
+        )
+    }
+    fn run(&self, args: Option<&clap::ArgMatches>) -> Result<(), Box<dyn std::error::Error>> {
+        let args = args.unwrap();
+        let eol = match args.value_of("convert") {
+            Some("crlf") => Some(EEol::ECrLf),
+            Some(_) => Some(EEol::ELf),
+            None => None,
+        };
+        let encoding = match args.value_of("encoding") {
+            Some(label) => Some(labels::parse_encoding(label)?),
+            None => None,
+        };
+        let dry_run = args.is_present("dry_run");
+
+        let mut cfg = ListFilesConfig::new();
+        cfg.set_folder(args.value_of("path").unwrap());
+        cfg.set_recursive(args.is_present("recursive"));
+        cfg.set_selection(args)?;
+
+        let mut out = Output::new(Some(args));
+        let mut failed = 0;
+        for entry in cfg.create_walker()? {
+            if entry.is_err() {
+                eprintln!("{}", entry.err().unwrap());
+                failed += 1;
+                continue;
+            }
+
+            let entry = entry.unwrap();
+            if cfg.filter(&entry) { continue; }
+
+            let path = entry.path();
+            let data = detect::read_file(path.to_str().unwrap());
+            if data.is_err() {
+                eprintln!("Failed to read file {}. Error: {}", path.display(), data.err().unwrap());
+                failed += 1;
+                continue;
+            }
+
+            let data = data.unwrap();
+            if !encoding.is_some_and(|e| detect::is_wide(&e)) && detect::is_binary(&data) {
+                out.record(Record::new()
+                    .field("path", path.display().to_string())
+                    .field("style", "binary")
+                    .text(format!("{:<6} {}", "binary", path.display())));
+                continue;
+            }
+
+            let units = Units::new(&data, encoding);
+            let stats = units.stats();
+            let record = Record::new()
+                .field("path", path.display().to_string())
+                .field("style", stats.style())
+                .field("crlf", stats.crlf)
+                .field("lf", stats.lf)
+                .field("cr", stats.cr);
+            if eol.is_none() {
+                out.record(record.text(format!("{:<6} {} (crlf: {}, lf: {}, cr: {})", stats.style(), path.display(), stats.crlf, stats.lf, stats.cr)));
+                continue;
+            }
+
+            let result = units.convert(eol.as_ref().unwrap());
+            if result == data { continue; }
+            out.record(record
+                .field("status", if dry_run { "would convert" } else { "converted" })
+                .field("target", args.value_of("convert").unwrap())
+                .text(format!("{}{} -> {}: {}",
+                              if dry_run { "would convert " } else { "" }, stats.style(), args.value_of("convert").unwrap(), path.display())));
+            if dry_run { continue; }
+
+            let write_result = backup::write_file(path, &result, None);
+            if write_result.is_err() {
+                eprintln!("Failed to write file {}. Error: {}", path.display(), write_result.err().unwrap());
+                failed += 1;
+            }
+        }
+        if failed > 0 {
+            return Err(common::errors::ErrorString::new(format!("Failed to process {} file(s)", failed)));
+        }
+        Ok(())
+    }
+}
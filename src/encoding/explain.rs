@@ -0,0 +1,129 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::common;
+use crate::common::output::{Output, Record};
+
+use super::detect;
+use super::detect::EBomPolicy;
+use super::encoding::EncodingRef;
+use super::labels;
+use super::stream;
+use super::traps::Issue;
+
+pub struct ExplainCmd;
+
+impl ExplainCmd {
+    fn record(filepath: &str, kind: &str, issue: Option<&Issue>, message: &str) -> Record {
+        Record::new()
+            .field("path", filepath)
+            .field("kind", kind)
+            .field("offset", issue.map(|i| i.offset))
+            .field("line", issue.map(|i| i.line))
+            .field("column", issue.map(|i| i.column))
+            .field("input", issue.map(|i| i.input.clone()))
+            .field("message", message)
+    }
+    fn describe_bom(out: &mut Output, filepath: &str, head: &[u8], e: &EncodingRef, bom_policy: &EBomPolicy) {
+        let bom = detect::detect_bom(head);
+        let found = bom.map_or(String::from("none"), |b| format!("{} BOM", b.name()));
+        let required = match bom_policy {
+            EBomPolicy::EIgnore => "any",
+            EBomPolicy::EWithBom => "with BOM",
+            EBomPolicy::EWithoutBom => "without BOM",
+        };
+        match detect::check_bom_policy(head, e, bom_policy).reason() {
+            Some(reason) => out.record(Self::record(filepath, "bom", None, &reason)
+                .text(format!("BOM: {}, policy: {}. The BOM policy is a reason: {}", found, required, reason))),
+            None => out.text(format!("BOM: {}, policy: {}. The BOM policy is satisfied", found, required)),
+        }
+    }
+    /// Hex and text dump of `context` bytes around the invalid sequence, the sequence itself is bracketed.
+    fn dump(file: &mut std::fs::File, issue: &Issue, context: usize) -> std::io::Result<String> {
+        let len = issue.input.split_whitespace().count();
+        let start = issue.offset.saturating_sub(context);
+        let mut data = Vec::new();
+        file.seek(SeekFrom::Start(start as u64))?;
+        file.take((issue.offset - start + len + context) as u64).read_to_end(&mut data)?;
+
+        let mut hex = String::new();
+        for (i, b) in data.iter().enumerate() {
+            let pos = start + i;
+            if pos == issue.offset { hex.push('['); } else if i > 0 && pos != issue.offset + len { hex.push(' '); }
+            hex.push_str(&format!("{:02x}", b));
+            if pos + 1 == issue.offset + len { hex.push(']'); }
+        }
+        let text: String = data.iter().map(|b| if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' }).collect();
+        Ok(format!("  {:08x}  {}  |{}|", start, hex, text))
+    }
+}
+
+impl common::Command for ExplainCmd {
+    fn create() -> Box<Self> { Box::<Self>::new(Self {}) }
+    fn name() -> &'static str { "explain" }
+    fn fill_subcommand<'a, 'b>(&self, app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+        app.subcommand(
+            clap::App::new(Self::name())
+                .about("shows why a file is not valid in the encoding")
+                .arg(clap::Arg::with_name("filepath").required(true))
+                .arg(clap::Arg::with_name("encoding").required(true))
+                .arg(
+                    clap::Arg::with_name("with_bom")
+                        .long("with_bom")
+                        .short("w")
+                        .conflicts_with("without_bom"))
+                .arg(
+                    clap::Arg::with_name("without_bom")
+                        .long("without_bom"))
+                .arg(
+                    clap::Arg::with_name("count")
+                        .long("count")
+                        .short("n")
+                        .takes_value(true)
+                        .default_value("10")
+                        .help("number of invalid sequences to show"))
+                .arg(
+                    clap::Arg::with_name("context")
+                        .long("context")
+                        .takes_value(true)
+                        .default_value("8")
+                        .help("bytes to dump before and after each invalid sequence"))
+        )
+    }
+    fn run(&self, args: Option<&clap::ArgMatches>) -> Result<(), Box<dyn std::error::Error>> {
+        let args = args.unwrap();
+        let filepath = args.value_of("filepath").unwrap();
+        let e = labels::parse_encoding(args.value_of("encoding").unwrap())?;
+        let bom_policy =
+            if args.is_present("with_bom") { EBomPolicy::EWithBom } else if args.is_present("without_bom") { EBomPolicy::EWithoutBom } else { EBomPolicy::EIgnore };
+        let count = args.value_of("count").unwrap().parse::<usize>()?;
+        let context = args.value_of("context").unwrap().parse::<usize>()?;
+
+        let mut out = Output::new(Some(args));
+        let mut file = std::fs::File::open(filepath)?;
+        let head = stream::read_sample(&mut file, detect::BINARY_SAMPLE_SIZE)?;
+        if !detect::is_wide(&e) && detect::is_binary(&head) {
+            out.text(format!("{}: looks like a binary file, it is not checked as {}", filepath, e.name()));
+            return Ok(());
+        }
+        Self::describe_bom(&mut out, filepath, &head, &e, &bom_policy);
+
+        // one more than shown tells whether the list is complete
+        let issues = detect::find_invalid_sequences(&mut std::io::Cursor::new(head).chain(&mut file), &e, count + 1)?;
+        if issues.is_empty() {
+            out.text(format!("{}: valid {}", filepath, e.name()));
+            return Ok(());
+        }
+
+        out.text(format!("{}: not valid {}", filepath, e.name()));
+        for (i, issue) in issues.iter().take(count).enumerate() {
+            let dump = Self::dump(&mut file, issue, context)?;
+            out.record(Self::record(filepath, "sequence", Some(issue), dump.trim_start())
+                .text(format!("{}. invalid sequence {} at byte {}, line {}, column {}\n{}",
+                              i + 1, issue.input, issue.offset, issue.line, issue.column, dump)));
+        }
+        if issues.len() > count {
+            out.text("More invalid sequences follow, use --count to show more");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+use std::io::Read;
+
+use crate::common;
+use crate::common::output::{Output, Record};
+
+use super::detect;
+use super::detector;
+use super::encoding::EncodingRef;
+use super::labels;
+use super::policy::Policy;
+use super::stream;
+use super::ConvertCmd;
+use super::ListFiles;
+use super::ListFilesConfig;
+
+/// Matching line, or only the matched text, with its 1-based position in characters.
+struct GrepMatch {
+    line: usize,
+    column: usize,
+    text: String,
+}
+
+/// Matches in a file, `None` for binary files.
+type GrepResult = Result<Option<Vec<GrepMatch>>, String>;
+
+pub struct GrepCmd;
+
+impl GrepCmd {
+    /// The encoding given on the command line, then the policy rule, then the detected one.
+    fn decoder_for(
+        cfg: &ListFilesConfig,
+        encoding: Option<EncodingRef>,
+        path: &str,
+        sample: &[u8],
+        complete: bool,
+    ) -> Result<(EncodingRef, usize), Box<dyn std::error::Error>> {
+        let configured = encoding.or_else(|| cfg.rule_for(path).map(|r| r.encoding));
+        if configured.is_none() { return ConvertCmd::detect_decoder(sample, complete, true); }
+
+        let e = configured.unwrap();
+        Ok((e, detect::bom_len(sample, &e)))
+    }
+    fn grep_file(
+        cfg: &ListFilesConfig,
+        encoding: Option<EncodingRef>,
+        regex: &regex::Regex,
+        only_matching: bool,
+        path: &str,
+    ) -> Result<Option<Vec<GrepMatch>>, Box<dyn std::error::Error>> {
+        let mut file = std::fs::File::open(path)?;
+        let sample = stream::read_sample(&mut file, detector::SAMPLE_SIZE)?;
+        if !encoding.is_some_and(|e| detect::is_wide(&e)) && detect::is_binary(&sample) { return Ok(None); }
+
+        let (e, bom_len) = Self::decoder_for(cfg, encoding, path, &sample, sample.len() < detector::SAMPLE_SIZE)?;
+        let mut matches = Vec::new();
+        let mut reader = std::io::Cursor::new(&sample[bom_len..]).chain(file);
+        detect::for_each_line(&mut reader, &e, &mut |number, line| {
+            for m in regex.find_iter(line) {
+                let column = line[..m.start()].chars().count() + 1;
+                let text = if only_matching { m.as_str() } else { line };
+                matches.push(GrepMatch { line: number, column, text: String::from(text) });
+                if !only_matching { break; }
+            }
+        })?;
+        Ok(Some(matches))
+    }
+    fn print(out: &mut Output, path: &str, result: &GrepResult) {
+        match result {
+            Ok(matches) => {
+                for m in matches.iter().flatten() {
+                    out.record(Record::new()
+                        .field("path", path)
+                        .field("line", m.line)
+                        .field("column", m.column)
+                        .field("text", &m.text)
+                        .text(format!("{}:{}:{}: {}", path, m.line, m.column, m.text)));
+                }
+            }
+            Err(msg) => eprintln!("{}: error: {}", path, msg),
+        }
+    }
+}
+
+impl common::Command for GrepCmd {
+    fn create() -> Box<Self> { Box::<Self>::new(Self {}) }
+    fn name() -> &'static str { "grep" }
+    fn fill_subcommand<'a, 'b>(&self, app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+        app.subcommand(
+            ListFilesConfig::add_selection_args(clap::App::new(Self::name()))
+                .about("searches the decoded text of files in any encoding with a regular expression")
+                .arg(clap::Arg::with_name("pattern").required(true))
+                .arg(clap::Arg::with_name("folder").required(true))
+                .arg(
+                    clap::Arg::with_name("encoding")
+                        .long("encoding")
+                        .takes_value(true)
+                        .help("encoding of all files, by default it is taken from the policy file or detected per file"))
+                .arg(
+                    clap::Arg::with_name("recursive")
+                        .long("recursive")
+                        .short("r"))
+                .arg(
+                    clap::Arg::with_name("ignore_case")
+                        .long("ignore_case")
+                        .short("i"))
+                .arg(
+                    clap::Arg::with_name("only_matching")
+                        .long("only_matching")
+                        .short("o")
+                        .help("print every match instead of the first match of each line with the whole line"))
+                .arg(
+                    clap::Arg::with_name("policy")
+                        .long("policy")
+                        .takes_value(true)
+                        .help("policy file with encodings per glob, by default .wtool-encoding.toml is looked up in the folder and its parents"))
+                .arg(
+                    clap::Arg::with_name("unordered")
+                        .long("unordered")
+                        .help("print matches as soon as a file is searched instead of sorted by path"))
+        )
+    }
+    fn run(&self, args: Option<&clap::ArgMatches>) -> Result<(), Box<dyn std::error::Error>> {
+        let args = args.unwrap();
+        let folder = args.value_of("folder").unwrap();
+        let regex = regex::RegexBuilder::new(args.value_of("pattern").unwrap())
+            .case_insensitive(args.is_present("ignore_case"))
+            .build()?;
+        let encoding = match args.value_of("encoding") {
+            Some(label) => Some(labels::parse_encoding(label)?),
+            None => None,
+        };
+        let only_matching = args.is_present("only_matching");
+
+        let mut cfg = ListFilesConfig::new();
+        cfg.set_folder(folder);
+        if encoding.is_none() { cfg.set_policy(Policy::open(args.value_of("policy"), folder)?, false); }
+        cfg.set_recursive(args.is_present("recursive"));
+        cfg.set_unordered(args.is_present("unordered"));
+        cfg.set_selection(args)?;
+
+        let process = |entry: &ignore::DirEntry| -> GrepResult {
+            let path = entry.path().to_str().unwrap();
+            let result = Self::grep_file(&cfg, encoding, &regex, only_matching, path);
+            if result.is_err() { return Err(result.err().unwrap().to_string()); }
+            Ok(result.unwrap())
+        };
+        let mut out = Output::new(Some(args));
+        ListFiles::walk_with(&cfg, process, |path, result| Self::print(&mut out, path, result))?;
+        Ok(())
+    }
+}
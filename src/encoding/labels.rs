@@ -0,0 +1,90 @@
+use crate::common;
+
+use super::codecs;
+use super::encoding::EncodingRef;
+
+/// Labels accepted by `encoding_from_whatwg_label`, the crate has no way to enumerate them.
+const WHATWG_LABELS: [&str; 216] = [
+    "unicode-1-1-utf-8", "utf-8", "utf8", "866", "cp866", "csibm866", "ibm866", "csisolatin2", "iso-8859-2",
+    "iso-ir-101", "iso8859-2", "iso88592", "iso_8859-2", "iso_8859-2:1987", "l2", "latin2", "csisolatin3",
+    "iso-8859-3", "iso-ir-109", "iso8859-3", "iso88593", "iso_8859-3", "iso_8859-3:1988", "l3", "latin3",
+    "csisolatin4", "iso-8859-4", "iso-ir-110", "iso8859-4", "iso88594", "iso_8859-4", "iso_8859-4:1988", "l4",
+    "latin4", "csisolatincyrillic", "cyrillic", "iso-8859-5", "iso-ir-144", "iso8859-5", "iso88595",
+    "iso_8859-5", "iso_8859-5:1988", "arabic", "asmo-708", "csiso88596e", "csiso88596i", "csisolatinarabic",
+    "ecma-114", "iso-8859-6", "iso-8859-6-e", "iso-8859-6-i", "iso-ir-127", "iso8859-6", "iso88596",
+    "iso_8859-6", "iso_8859-6:1987", "csisolatingreek", "ecma-118", "elot_928", "greek", "greek8", "iso-8859-7",
+    "iso-ir-126", "iso8859-7", "iso88597", "iso_8859-7", "iso_8859-7:1987", "sun_eu_greek", "csiso88598e",
+    "csisolatinhebrew", "hebrew", "iso-8859-8", "iso-8859-8-e", "iso-ir-138", "iso8859-8", "iso88598",
+    "iso_8859-8", "iso_8859-8:1988", "visual", "csiso88598i", "iso-8859-8-i", "logical", "csisolatin6",
+    "iso-8859-10", "iso-ir-157", "iso8859-10", "iso885910", "l6", "latin6", "iso-8859-13", "iso8859-13",
+    "iso885913", "iso-8859-14", "iso8859-14", "iso885914", "csisolatin9", "iso-8859-15", "iso8859-15",
+    "iso885915", "iso_8859-15", "l9", "iso-8859-16", "cskoi8r", "koi", "koi8", "koi8-r", "koi8_r", "koi8-u",
+    "csmacintosh", "mac", "macintosh", "x-mac-roman", "dos-874", "iso-8859-11", "iso8859-11", "iso885911",
+    "tis-620", "windows-874", "cp1250", "windows-1250", "x-cp1250", "cp1251", "windows-1251", "x-cp1251",
+    "ansi_x3.4-1968", "ascii", "cp1252", "cp819", "csisolatin1", "ibm819", "iso-8859-1", "iso-ir-100",
+    "iso8859-1", "iso88591", "iso_8859-1", "iso_8859-1:1987", "l1", "latin1", "us-ascii", "windows-1252",
+    "x-cp1252", "cp1253", "windows-1253", "x-cp1253", "cp1254", "csisolatin5", "iso-8859-9", "iso-ir-148",
+    "iso8859-9", "iso88599", "iso_8859-9", "iso_8859-9:1989", "l5", "latin5", "windows-1254", "x-cp1254",
+    "cp1255", "windows-1255", "x-cp1255", "cp1256", "windows-1256", "x-cp1256", "cp1257", "windows-1257",
+    "x-cp1257", "cp1258", "windows-1258", "x-cp1258", "x-mac-cyrillic", "x-mac-ukrainian", "chinese",
+    "csgb2312", "csiso58gb231280", "gb2312", "gb_2312", "gb_2312-80", "gbk", "iso-ir-58", "x-gbk", "gb18030",
+    "big5", "big5-hkscs", "cn-big5", "csbig5", "x-x-big5", "cseucpkdfmtjapanese", "euc-jp", "x-euc-jp",
+    "csiso2022jp", "iso-2022-jp", "csshiftjis", "ms_kanji", "shift-jis", "shift_jis", "sjis", "windows-31j",
+    "x-sjis", "cseuckr", "csksc56011987", "euc-kr", "iso-ir-149", "korean", "ks_c_5601-1987", "ks_c_5601-1989",
+    "ksc5601", "ksc_5601", "windows-949", "csiso2022kr", "hz-gb-2312", "iso-2022-kr", "iso-2022-cn",
+    "iso-2022-cn-ext", "utf-16be", "utf-16", "utf-16le", "x-user-defined",
+];
+
+/// Windows code pages known to `encoding_from_windows_code_page`. The crate maps Mac Cyrillic to 1259,
+/// it is the Mac OS 9 variant which Windows calls Mac Ukrainian, 10017. 10007 is the classic table in `codecs`.
+const CODE_PAGES: [usize; 37] = [
+    65001, 1200, 1201, 866, 874, 1250, 1251, 1252, 1253, 1254, 1255, 1256, 1257, 1258, 10000, 10017,
+    20866, 21866, 28591, 28592, 28593, 28594, 28595, 28596, 28597, 28598, 38598, 28603, 28605,
+    932, 936, 949, 950, 20932, 50220, 52936, 54936,
+];
+
+fn encoding_from_code_page(cp: usize) -> Option<EncodingRef> {
+    let e = codecs::encoding_from_code_page(cp);
+    if e.is_some() { return e; }
+    if cp == 10017 { return Some(encoding::all::MAC_CYRILLIC as EncodingRef); }
+    encoding::label::encoding_from_windows_code_page(cp)
+}
+
+/// Accepts WHATWG labels and Windows code pages as `1251` or `cp65001`.
+pub(super) fn encoding_from_label(label: &str) -> Option<EncodingRef> {
+    let e = encoding::label::encoding_from_whatwg_label(label).or_else(|| codecs::encoding_from_label(label));
+    if e.is_some() { return e; }
+
+    let label = label.trim().to_lowercase();
+    let number = label.trim_start_matches("cp").trim_start_matches("windows-");
+    let cp = number.parse::<usize>();
+    if cp.is_err() { return None; }
+    encoding_from_code_page(cp.unwrap())
+}
+
+pub(super) fn parse_encoding(label: &str) -> Result<EncodingRef, Box<dyn std::error::Error>> {
+    let e = encoding_from_label(label);
+    if e.is_none() {
+        return Err(common::errors::ErrorString::new(format!(
+            "Unknown encoding {}. Use 'encoding encodings' to list supported names and code pages", label)));
+    }
+    Ok(e.unwrap())
+}
+
+pub(super) fn labels(e: &EncodingRef) -> Vec<&'static str> {
+    let mut labels: Vec<&'static str> = WHATWG_LABELS.iter()
+        .filter(|label| encoding::label::encoding_from_whatwg_label(label).is_some_and(|l| l.name() == e.name()))
+        .cloned()
+        .collect();
+    labels.extend(codecs::codec_labels(e).0);
+    labels
+}
+
+pub(super) fn code_pages(e: &EncodingRef) -> Vec<usize> {
+    let mut code_pages: Vec<usize> = CODE_PAGES.iter()
+        .filter(|cp| encoding_from_code_page(**cp).is_some_and(|c| c.name() == e.name()))
+        .cloned()
+        .collect();
+    code_pages.extend(codecs::codec_labels(e).1);
+    code_pages
+}
@@ -1,14 +1,30 @@
 extern crate encoding;
 
 use std::io::Read;
-use std::io::Write;
 
 use encoding::types::EncodingRef;
 
 use crate::common;
+use crate::common::output::{Output, Record};
 use crate::encoding::detect::EBomPolicy;
 
+mod backup;
+mod bom;
+mod check;
+mod codecs;
 mod detect;
+mod detector;
+mod eol;
+mod explain;
+mod grep;
+mod labels;
+mod mojibake;
+mod normalize;
+mod policy;
+mod report;
+mod stream;
+mod traps;
+mod verify;
 
 pub struct EncodingDispatcher {
     m_disp: common::Dispatcher,
@@ -21,9 +37,19 @@ impl EncodingDispatcher {
         };
         disp.m_disp
             .add_cmd::<ConvertCmd>()
+            .add_cmd::<bom::BomCmd>()
+            .add_cmd::<check::CheckCmd>()
             .add_cmd::<detect::DetectEncodingCmd>()
+            .add_cmd::<eol::EolCmd>()
+            .add_cmd::<explain::ExplainCmd>()
+            .add_cmd::<grep::GrepCmd>()
             .add_cmd::<ListEncodings>()
-            .add_cmd::<ListFiles>();
+            .add_cmd::<ListFiles>()
+            .add_cmd::<mojibake::MojibakeCmd>()
+            .add_cmd::<normalize::NormalizeCmd>()
+            .add_cmd::<report::ReportCmd>()
+            .add_cmd::<verify::VerifyCmd>()
+            .add_cmd::<backup::RestoreCmd>();
         disp
     }
 }
@@ -46,89 +72,337 @@ impl common::Command for EncodingDispatcher {
 
 pub struct ConvertCmd;
 
-impl ConvertCmd {
-    fn read_file(path: &str) -> std::io::Result<Vec<u8>> {
-        let mut file = std::fs::OpenOptions::new()
-            .read(true)
-            .write(false)
-            .create(false)
-            .open(path).unwrap();
-        let mut buf = Vec::<u8>::new();
-        file.read_to_end(&mut buf)?;
-        Ok(buf)
-    }
-    fn write_file(path: &str, data: &Vec<u8>) -> std::io::Result<()> {
-        let mut file = std::fs::OpenOptions::new()
-            .read(false)
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(path).unwrap();
-        file.write_all(&data[..])?;
-        file.flush()?;
+struct ConvertConfig {
+    decoder_ref: Option<EncodingRef>,
+    encoder_ref: EncodingRef,
+    force: bool,
+    extension: Option<String>,
+    journal: Option<backup::Journal>,
+    policy: Option<policy::Policy>,
+    dry_run: bool,
+    diff: bool,
+    verify: bool,
+    decoder_trap: encoding::types::DecoderTrap,
+    encoder_trap: encoding::types::EncoderTrap,
+    output: std::cell::RefCell<Output>,
+}
+
+impl ConvertConfig {
+    fn new(encoder_ref: EncodingRef) -> Self {
+        Self {
+            decoder_ref: None,
+            encoder_ref,
+            force: false,
+            extension: None,
+            journal: None,
+            policy: None,
+            dry_run: false,
+            diff: false,
+            verify: false,
+            decoder_trap: encoding::types::DecoderTrap::Strict,
+            encoder_trap: encoding::types::EncoderTrap::Strict,
+            output: std::cell::RefCell::new(Output::new(None)),
+        }
+    }
+    fn set_decoder(&mut self, decoder_ref: EncodingRef) {
+        self.decoder_ref = Some(decoder_ref);
+    }
+    fn set_force(&mut self, force: bool) {
+        self.force = force;
+    }
+    fn set_extension(&mut self, extension: &str) {
+        self.extension = Some(String::from(extension.trim_start_matches('.')));
+    }
+    fn set_backup(&mut self) -> std::io::Result<()> {
+        self.journal = Some(backup::Journal::create()?);
         Ok(())
     }
-    /*fn decode(data: &Vec<u8>) -> (Option<String>, encoding::types::EncodingRef) {
-        let encodings = encoding::all::encodings();
-        let trap = encoding::types::DecoderTrap::Strict;
-        for en in encodings {
-            let fail_back_encoder = *en;
-            let (result, encoder_ref) = encoding::types::decode(&data[..], trap, fail_back_encoder);
-            if result.is_ok() {
-                return (Some(result.unwrap()), encoder_ref);
-            }
+    /// Informational output goes to stderr when stdout carries the converted data.
+    fn set_output(&mut self, args: &clap::ArgMatches, to_stdout: bool) {
+        self.output = std::cell::RefCell::new(if to_stdout { Output::stderr(Some(args)) } else { Output::new(Some(args)) });
+    }
+    fn set_policy(&mut self, policy: Option<policy::Policy>) {
+        self.policy = policy;
+    }
+    fn set_dry_run(&mut self, dry_run: bool, diff: bool) {
+        self.dry_run = dry_run;
+        self.diff = diff;
+    }
+    fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+    }
+    fn rule_for(&self, path: &str) -> Option<&policy::Rule> {
+        self.policy.as_ref().and_then(|p| p.rule_for(path))
+    }
+    fn log(&self, msg: std::fmt::Arguments) {
+        self.output.borrow_mut().text(msg);
+    }
+    fn record(&self, record: Record) {
+        self.output.borrow_mut().record(record);
+    }
+    fn set_traps(&mut self, decoder_trap: &str, encoder_trap: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.decoder_trap = traps::parse_decoder_trap(decoder_trap)?;
+        self.encoder_trap = traps::parse_encoder_trap(encoder_trap)?;
+        Ok(())
+    }
+    fn is_extension_match(&self, path: &std::path::Path) -> bool {
+        if self.extension.is_none() { return true; }
+        match path.extension() {
+            Some(ext) => ext.to_str() == self.extension.as_deref(),
+            None => false,
+        }
+    }
+}
+
+impl ConvertCmd {
+    fn detect_decoder(data: &[u8], complete: bool, force: bool) -> Result<(EncodingRef, usize), Box<dyn std::error::Error>> {
+        let ranked = detector::detect_encoding(data, complete);
+        if ranked.is_empty() {
+            return Err(common::errors::ErrorStr::new("Failed to detect source encoding"));
+        }
+        if detector::is_ambiguous(&ranked) && !force {
+            let names: Vec<&str> = detector::top_candidates(&ranked).iter()
+                .map(|d| d.encoding.name())
+                .collect();
+            return Err(common::errors::ErrorString::new(format!(
+                "Source encoding is ambiguous: {}. Use --src_codepage or --force", names.join(", "))));
         }
-        (None, encoding::all::ERROR)
-    }*/
+        Ok((ranked[0].encoding, ranked[0].bom_len))
+    }
+    fn file_record(status: &str, source: &str, target: &str, decoder: &str, encoder: &str) -> Record {
+        Record::new()
+            .field("status", status)
+            .field("source", source)
+            .field("target", target)
+            .field("decoder", decoder)
+            .field("encoder", encoder)
+    }
+    fn write_target<F>(tgt_path: &str, cfg: &ConvertConfig, write: F) -> Result<(), Box<dyn std::error::Error>>
+        where F: FnOnce(&mut dyn std::io::Write) -> Result<(), Box<dyn std::error::Error>> {
+        if tgt_path == stream::STDIO_PATH {
+            let stdout = std::io::stdout();
+            let mut writer = std::io::BufWriter::new(stdout.lock());
+            return write(&mut writer);
+        }
+        backup::write_with(std::path::Path::new(tgt_path), cfg.journal.as_ref(), write)
+    }
+    /// Returns `false` if a dry run found characters the strict traps would reject, the conversion would fail on them.
     fn convert_file(
         src_path: &str,
         tgt_path: &str,
-        decoder_ref: EncodingRef,
-        encoder_ref: EncodingRef,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let file_data = Self::read_file(src_path).unwrap();
-        if file_data.is_empty() { return Ok(()); }
+        cfg: &ConvertConfig,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let (mut src, src_size) = stream::open_input(src_path)?;
+        let sample = stream::read_sample(&mut src, detector::SAMPLE_SIZE)?;
+        let is_complete = sample.len() < detector::SAMPLE_SIZE;
+        let rule = cfg.rule_for(src_path);
+        let encoder_ref = rule.map_or(cfg.encoder_ref, |r| r.encoding);
+        // a rule with BOM or line ending requirements always rewrites the file
+        let keep_layout = rule.is_none_or(|r| r.eol.is_none() && matches!(r.bom, EBomPolicy::EIgnore));
+        let src_name = stream::display_name(src_path);
+        if !cfg.decoder_ref.is_some_and(|e| detect::is_wide(&e)) && detect::is_binary(&sample) {
+            return Err(common::errors::ErrorString::new(format!("{} looks like a binary file", src_name)));
+        }
 
-        //let (result, decoder_ref) = Self::decode(&file_data);
-        let result = decoder_ref.decode(&file_data[..], encoding::types::DecoderTrap::Strict)?;
-        let result = encoder_ref.encode(result.as_str(), encoding::types::EncoderTrap::Strict)?;
-        Self::write_file(tgt_path, &result)?;
+        let (decoder_ref, mut bom_len) =
+            match cfg.decoder_ref { Some(e) => (e, 0), None => Self::detect_decoder(&sample, is_complete, cfg.force)? };
+        if !keep_layout { bom_len = detect::bom_len(&sample, &decoder_ref); }
+        if cfg.decoder_ref.is_none() && decoder_ref.name() == encoder_ref.name() && keep_layout {
+            if !cfg.dry_run && (src_path != tgt_path || tgt_path == stream::STDIO_PATH) {
+                Self::write_target(tgt_path, cfg, |writer| {
+                    let mut reader = std::io::Cursor::new(sample).chain(src);
+                    std::io::copy(&mut reader, writer)?;
+                    Ok(())
+                })?;
+            }
+            cfg.record(Self::file_record("unchanged", src_name, tgt_path, decoder_ref.name(), encoder_ref.name())
+                .text(format!("Already in target encoding. Source file: {}, Target file {}, Encoding: {}",
+                              src_name, tgt_path, encoder_ref.name())));
+            return Ok(true);
+        }
 
-        println!("Converting complete. Source file: {}, Target file {}, Decoder: {}, Encoder: {}",
-                 src_path, tgt_path, decoder_ref.name(), encoder_ref.name());
-        Ok(())
+        // a dry run replaces what the strict traps reject to find all of it instead of stopping at the first one
+        let strict_decoder = matches!(cfg.decoder_trap, encoding::types::DecoderTrap::Strict);
+        let strict_encoder = matches!(cfg.encoder_trap, encoding::types::EncoderTrap::Strict);
+        let decoder_trap = if cfg.dry_run && strict_decoder { encoding::types::DecoderTrap::Replace } else { cfg.decoder_trap };
+        let encoder_trap = if cfg.dry_run && strict_encoder { encoding::types::EncoderTrap::Replace } else { cfg.encoder_trap };
+        let mut issues = Vec::<traps::Issue>::new();
+        let mut decoder = stream::StreamDecoder::new(decoder_ref, decoder_trap, bom_len);
+        let mut encoder = stream::StreamEncoder::new(encoder_ref, encoder_trap);
+        let mut eol = rule.and_then(|r| r.eol.as_ref()).map(eol::EolConverter::new);
+        let write_bom = rule.is_some_and(|r| matches!(r.bom, EBomPolicy::EWithBom));
+        let mut progress = stream::Progress::new(src_name, src_size);
+        let mut sample = std::io::Cursor::new(sample);
+        sample.set_position(bom_len as u64);
+        let mut reader: Box<dyn Read> = Box::new(sample.chain(src));
+        if cfg.diff {
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data)?;
+            Self::print_diff(src_name, tgt_path, &data, decoder_ref, encoder_ref, cfg);
+            reader = Box::new(std::io::Cursor::new(data));
+        }
+        let transcode = |writer: &mut dyn std::io::Write| {
+            // moved in, so the source is closed when the closure returns
+            let mut reader = reader;
+            if write_bom { writer.write_all(&detect::get_bom_mark(&encoder_ref))?; }
+            stream::transcode(&mut reader, writer, &mut decoder, &mut encoder, eol.as_mut(), &mut issues,
+                              &mut |read| progress.update(read))
+        };
+        // the source is closed before the target is renamed over it
+        if cfg.dry_run { transcode(&mut std::io::sink())?; } else { Self::write_target(tgt_path, cfg, transcode)?; }
+        progress.finish();
+
+        let (mut invalid, mut unmappable) = (0, 0);
+        for issue in issues.iter_mut() {
+            if cfg.dry_run && issue.stage == "decode" && strict_decoder {
+                issue.kind = traps::decoder_action(&cfg.decoder_trap);
+                invalid += 1;
+            }
+            if cfg.dry_run && issue.stage == "encode" && strict_encoder {
+                issue.kind = traps::encoder_action(&cfg.encoder_trap);
+                unmappable += 1;
+            }
+        }
+        for issue in issues.iter() {
+            cfg.record(Self::file_record("issue", src_name, tgt_path, decoder_ref.name(), encoder_ref.name())
+                .field("line", issue.line)
+                .field("message", issue.to_string())
+                .text(format!("{}: {}", src_name, issue)));
+        }
+        if cfg.verify && !cfg.dry_run && tgt_path != stream::STDIO_PATH {
+            let expected = encoder.digest();
+            let found = verify::VerifyCmd::digest(tgt_path, encoder_ref, write_bom)?;
+            if found != expected {
+                return Err(common::errors::ErrorString::new(format!(
+                    "Verification failed: {} does not decode back to the text of {}, {} characters expected, {} found{}",
+                    tgt_path, src_name, expected.chars(), found.chars(),
+                    if issues.is_empty() { "" } else { ". Characters were replaced by the traps" })));
+            }
+        }
+
+        if invalid + unmappable > 0 {
+            let mut counts = Vec::new();
+            if unmappable > 0 { counts.push(format!("{} unmappable characters", unmappable)); }
+            if invalid > 0 { counts.push(format!("{} invalid sequences", invalid)); }
+            cfg.record(Self::file_record("would fail", src_name, tgt_path, decoder_ref.name(), encoder_ref.name())
+                .field("message", counts.join(", "))
+                .text(format!("Would fail: {}. Source file: {}, Target file {}, Decoder: {}, Encoder: {}",
+                              counts.join(", "), src_name, tgt_path, decoder_ref.name(), encoder_ref.name())));
+            return Ok(false);
+        }
+
+        let status = if cfg.dry_run { "would convert" } else { "converted" };
+        cfg.record(Self::file_record(status, src_name, tgt_path, decoder_ref.name(), encoder_ref.name())
+            .text(format!("{}. Source file: {}, Target file {}, Decoder: {}, Encoder: {}",
+                          if cfg.dry_run { "Would convert" } else { "Converting complete" },
+                          src_name, tgt_path, decoder_ref.name(), encoder_ref.name())));
+        Ok(true)
+    }
+    /// Prints source lines whose characters would be lost or altered by the target encoding.
+    /// Unmappable characters are shown as a non-strict encoder trap would write them, replaced by default.
+    fn print_diff(src_name: &str, tgt_path: &str, data: &[u8], decoder_ref: EncodingRef, encoder_ref: EncodingRef, cfg: &ConvertConfig) {
+        let text = decoder_ref.decode(data, encoding::types::DecoderTrap::Replace).unwrap();
+        let trap = match cfg.encoder_trap {
+            encoding::types::EncoderTrap::Strict => encoding::types::EncoderTrap::Replace,
+            trap => trap,
+        };
+
+        let mut header = false;
+        for (i, line) in text.split_terminator('\n').enumerate() {
+            let encoded = encoder_ref.encode(line, trap).unwrap();
+            let result = encoder_ref.decode(&encoded, encoding::types::DecoderTrap::Replace).unwrap();
+            if result == line && !line.contains('\u{FFFD}') { continue; }
+            if !header {
+                cfg.log(format_args!("--- {} ({})", src_name, decoder_ref.name()));
+                cfg.log(format_args!("+++ {} ({})", tgt_path, encoder_ref.name()));
+                header = true;
+            }
+            let (line, result) = (line.trim_end_matches('\r'), result.trim_end_matches('\r'));
+            cfg.record(Self::file_record("altered", src_name, tgt_path, decoder_ref.name(), encoder_ref.name())
+                .field("line", i + 1)
+                .field("message", result)
+                .text(format!("@@ -{} +{} @@\n-{}\n+{}", i + 1, i + 1, line, result)));
+        }
+        if !header {
+            cfg.log(format_args!("{}: no characters would be lost or altered", src_name));
+        }
     }
+    /// Converts the files `files` selects, ignore files are honored and the walk configuration files are left alone.
     fn convert_folder(
         src_path: &str,
         tgt_path: &str,
-        decoder_ref: EncodingRef,
-        encoder_ref: EncodingRef,
-        extension: Option<&str>,
-        recursive: bool,
-    ) {
-        let mut dirs = std::collections::VecDeque::<(String, String)>::new();
-        dirs.push_back((String::from(src_path), String::from(tgt_path)));
-
-        /*while !dirs.is_empty() {
-            let (src_path, tgt_path) = dirs.pop_front().unwrap();
-            let read_dir_res = std::fs::read_dir(src_path);
-            for entry in read_dir_res.into_iter() {
-                //let entry = entry?;
-                let path = entry.path();
-                if path.is_dir() {
-                    let file_name = entry.file_name().unwrap();
-                    let tgt_path = tgt_path + file_name;
-                    dirs.push_back(String::from(path), tgt_path);
-                } else if path.is_file() {
-                    if extension.is_some() {
-                        if path.extension != extension {
-                            continue;
-                        }
-                    }
-                    Self::convert_file(path.to_str().unwrap(), tgt_path + entry.file_name().unwrap(), decoder_ref, encoder_ref);
+        cfg: &ConvertConfig,
+        files: &ListFilesConfig,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let src_root = std::path::Path::new(src_path);
+        let tgt_root = std::path::Path::new(tgt_path);
+        let walker = files.create_walker()?;
+
+        let mut converted = 0;
+        let mut skipped = 0;
+        let mut binary = 0;
+        let mut would_fail = 0;
+        let mut failed = 0;
+
+        for entry in walker {
+            if entry.is_err() {
+                eprintln!("{}", entry.err().unwrap());
+                failed += 1;
+                continue;
+            }
+
+            let entry = entry.unwrap();
+            let tgt_file = tgt_root.join(entry.path().strip_prefix(src_root)?);
+            if entry.file_type().is_some_and(|t| t.is_dir()) {
+                if !cfg.dry_run { std::fs::create_dir_all(&tgt_file)?; }
+                continue;
+            }
+            if files.filter(&entry) || WALK_CONFIG_FILES.iter().any(|name| entry.file_name() == *name) || !cfg.is_extension_match(entry.path()) {
+                skipped += 1;
+                continue;
+            }
+            if !cfg.decoder_ref.is_some_and(|e| detect::is_wide(&e)) {
+                let is_binary = detect::is_binary_file(entry.path().to_str().unwrap());
+                if is_binary.is_err() {
+                    eprintln!("Failed to read file {}. Error: {}", entry.path().display(), is_binary.err().unwrap());
+                    failed += 1;
+                    continue;
+                }
+                if is_binary.unwrap() {
+                    let path = entry.path().display().to_string();
+                    cfg.record(Self::file_record("binary", &path, tgt_file.to_str().unwrap(), "", "")
+                        .text(format!("Skipped binary file {}", path)));
+                    binary += 1;
+                    continue;
                 }
             }
-        }*/
+
+            let result = Self::convert_file(
+                entry.path().to_str().unwrap(),
+                tgt_file.to_str().unwrap(),
+                cfg);
+            if result.is_err() {
+                eprintln!("Failed to convert file {}. Error: {}", entry.path().display(), result.err().unwrap());
+                failed += 1;
+                continue;
+            }
+            if !result.unwrap() {
+                would_fail += 1;
+                continue;
+            }
+            converted += 1;
+        }
+
+        if cfg.dry_run {
+            cfg.log(format_args!("To convert: {}, Skipped: {}, Binary: {}, Would fail: {}, Failed: {}",
+                     converted, skipped, binary, would_fail, failed));
+        } else {
+            cfg.log(format_args!("Converted: {}, Skipped: {}, Binary: {}, Failed: {}", converted, skipped, binary, failed));
+        }
+        if failed > 0 {
+            return Err(common::errors::ErrorString::new(format!("Failed to convert {} file(s)", failed)));
+        }
+        Ok(())
     }
 }
 
@@ -141,8 +415,8 @@ impl common::Command for ConvertCmd {
     }
     fn fill_subcommand<'a, 'b>(&self, app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
         let sub_cmd =
-            clap::App::new(Self::name())
-                .arg(clap::Arg::with_name("path").required(true))
+            ListFilesConfig::add_selection_args(clap::App::new(Self::name()))
+                .arg(clap::Arg::with_name("path").required(true).help("source file or folder, - for stdin"))
                 .arg(
                     clap::Arg::with_name("folder")
                         .short("f")
@@ -154,25 +428,65 @@ impl common::Command for ConvertCmd {
                 .arg(
                     clap::Arg::with_name("source_codepage")
                         .long("src_codepage")
-                        .required(true)
                         .takes_value(true)
-                        .help("Supported encodings: utf8, cp1251, ..."))
+                        .help("Encoding label or Windows code page: utf8, cp1251, 1251, cp65001, ... Detected from BOM and content if omitted"))
                 .arg(
                     clap::Arg::with_name("target_codepage")
                         .long("tgt_codepage")
                         .required(true)
                         .takes_value(true)
-                        .help("Supported encodings: utf8, cp1251, ..."))
+                        .help("Encoding label or Windows code page: utf8, cp1251, 1251, cp65001, ..."))
                 .arg(
                     clap::Arg::with_name("target_path")
                         .long("tgt_path")
                         .takes_value(true)
-                        .help("result files path"))
+                        .help("result files path, - for stdout"))
                 .arg(
                     clap::Arg::with_name("extension")
                         .short("e")
                         .long("extension")
-                        .takes_value(true));
+                        .takes_value(true)
+                        .help("convert only files with this extension, e.g. txt"))
+                .arg(
+                    clap::Arg::with_name("force")
+                        .long("force")
+                        .help("use the best detected source encoding even if it is ambiguous"))
+                .arg(
+                    clap::Arg::with_name("policy")
+                        .long("policy")
+                        .takes_value(true)
+                        .help("policy file with target encodings per glob, by default .wtool-encoding.toml is looked up in the folder and its parents"))
+                .arg(
+                    clap::Arg::with_name("dry_run")
+                        .long("dry_run")
+                        .help("convert without writing anything and report what would happen"))
+                .arg(
+                    clap::Arg::with_name("diff")
+                        .long("diff")
+                        .requires("dry_run")
+                        .help("show source lines with characters the target encoding would lose or alter, loads the whole file into memory"))
+                .arg(
+                    clap::Arg::with_name("verify")
+                        .long("verify")
+                        .help("re-read each written file and fail if it does not decode back to the converted text"))
+                .arg(
+                    clap::Arg::with_name("backup")
+                        .long("backup")
+                        .help("keep .bak copies of overwritten files, 'encoding restore' rolls them back"))
+                .arg(
+                    clap::Arg::with_name("decoder_trap")
+                        .long("decoder_trap")
+                        .takes_value(true)
+                        .default_value("strict")
+                        .possible_values(&["strict", "replace", "ignore"])
+                        .help("what to do with bytes invalid in the source encoding"))
+                .arg(
+                    clap::Arg::with_name("encoder_trap")
+                        .long("encoder_trap")
+                        .takes_value(true)
+                        .default_value("strict")
+                        .possible_values(&["strict", "replace", "ignore", "ncr", "html"])
+                        .help("what to do with characters missing in the target encoding, ncr and html write &#NNNN;"));
 
         app.subcommand(sub_cmd)
     }
@@ -182,23 +496,43 @@ impl common::Command for ConvertCmd {
         let tgt_path =
             if args.is_present("target_path")
             { args.value_of("target_path").unwrap() } else { src_path };
-        let decoder = args.value_of("source_codepage").unwrap();
+        let decoder = args.value_of("source_codepage").unwrap_or("auto");
         let encoder = args.value_of("target_codepage").unwrap();
-        let decoder_ref = encoding::label::encoding_from_whatwg_label(decoder).unwrap();
-        let encoder_ref = encoding::label::encoding_from_whatwg_label(encoder).unwrap();
+        let encoder_ref = labels::parse_encoding(encoder)?;
         let is_folder = args.is_present("folder");
 
-        if decoder == encoder {
-            println!("Source and Target encoding are same");
-            return Ok(());
+        let mut cfg = ConvertConfig::new(encoder_ref);
+        cfg.set_output(args, tgt_path == stream::STDIO_PATH);
+        if is_folder && src_path != stream::STDIO_PATH { cfg.set_policy(policy::Policy::open(args.value_of("policy"), src_path)?); }
+        if args.is_present("source_codepage") {
+            let decoder_ref = labels::parse_encoding(decoder)?;
+            if decoder_ref.name() == encoder_ref.name() && cfg.policy.is_none() {
+                cfg.log(format_args!("Source and Target encoding are same"));
+                return Ok(());
+            }
+            cfg.set_decoder(decoder_ref);
         }
+        cfg.set_force(args.is_present("force"));
+        if args.is_present("extension") { cfg.set_extension(args.value_of("extension").unwrap()); }
+        cfg.set_dry_run(args.is_present("dry_run"), args.is_present("diff"));
+        if args.is_present("backup") && !cfg.dry_run { cfg.set_backup()?; }
+        cfg.set_verify(args.is_present("verify"));
+        cfg.set_traps(args.value_of("decoder_trap").unwrap(), args.value_of("encoder_trap").unwrap())?;
 
-        println!("Source path: [{}], Target path: [{}], Decoder: [{}], Encoder: [{}]", src_path, tgt_path, decoder, encoder);
+        cfg.log(format_args!("Source path: [{}], Target path: [{}], Decoder: [{}], Encoder: [{}]", src_path, tgt_path, decoder, encoder));
 
         if is_folder {
-            return Err(common::errors::ErrorStr::new("Folders not implemented"));
+            if src_path == stream::STDIO_PATH || tgt_path == stream::STDIO_PATH {
+                return Err(common::errors::ErrorStr::new("Folders can not be read from stdin or written to stdout"));
+            }
+            let mut files = ListFilesConfig::new();
+            files.set_folder(src_path);
+            files.set_recursive(args.is_present("recursive"));
+            files.set_selection(args)?;
+            return Self::convert_folder(src_path, tgt_path, &cfg, &files);
         }
-        Self::convert_file(src_path, tgt_path, decoder_ref, encoder_ref)
+        Self::convert_file(src_path, tgt_path, &cfg)?;
+        Ok(())
     }
 }
 
@@ -211,12 +545,22 @@ impl common::Command for ListEncodings {
         "encodings"
     }
     fn fill_subcommand<'a, 'b>(&self, app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
-        app.subcommand(clap::App::new(Self::name()))
+        app.subcommand(
+            clap::App::new(Self::name())
+                .about("lists supported encodings, codepage mapping files listed in WTOOL_CODEPAGES are added to them"))
     }
     fn run(&self, args: Option<&clap::ArgMatches>) -> Result<(), Box<dyn std::error::Error>> {
-        let encodings = encoding::all::encodings();
+        let encodings = codecs::encodings();
+        let mut out = Output::new(args);
+        out.text(format!("{:<20} {:<12} {}", "name", "code page", "labels"));
         for e in encodings {
-            println!("{}", e.name());
+            let code_pages = labels::code_pages(e);
+            let names: Vec<String> = code_pages.iter().map(|cp| cp.to_string()).collect();
+            out.record(Record::new()
+                .field("name", e.name())
+                .field("code_pages", code_pages)
+                .field("labels", labels::labels(e))
+                .text(format!("{:<20} {:<12} {}", e.name(), names.join(", "), labels::labels(e).join(", "))));
         }
         Ok(())
     }
@@ -232,15 +576,9 @@ impl common::Command for ListFiles {
     }
     fn fill_subcommand<'a, 'b>(&self, app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
         app.subcommand(
-            clap::App::new(Self::name())
+            ListFilesConfig::add_selection_args(clap::App::new(Self::name()))
                 .arg(clap::Arg::with_name("folder").required(true))
                 .arg(clap::Arg::with_name("encoding").required(true))
-                .arg(
-                    clap::Arg::with_name("regex")
-                        .long("regex")
-                        .required(false)
-                        .takes_value(true)
-                        .help("*.rs mask: (.*)+(.\\.rs)$"))
                 .arg(
                     clap::Arg::with_name("recursive")
                         .long("recursive")
@@ -264,6 +602,19 @@ impl common::Command for ListFiles {
                         .long("without_bom")
                         .takes_value(false)
                         .required(false))
+                .arg(
+                    clap::Arg::with_name("unordered")
+                        .long("unordered")
+                        .help("print files as soon as they are checked instead of sorted by path"))
+                .arg(
+                    clap::Arg::with_name("trust_bom")
+                        .long("trust_bom")
+                        .help("a BOM decides the encoding of a file, files with a BOM are not decoded"))
+                .arg(
+                    clap::Arg::with_name("why")
+                        .long("why")
+                        .requires("list_skipped")
+                        .help("print why skipped files do not match, 'encoding explain' shows details"))
         )
     }
     fn run(&self, args: Option<&clap::ArgMatches>) -> Result<(), Box<dyn std::error::Error>> {
@@ -271,17 +622,18 @@ impl common::Command for ListFiles {
 
         let args = args.unwrap();
         cfg.set_folder(args.value_of("folder").unwrap());
-        cfg.set_decoder(args.value_of("encoding").unwrap());
+        cfg.set_decoder(args.value_of("encoding").unwrap())?;
         if args.is_present("with_bom") { cfg.set_bom_policy(EBomPolicy::EWithBom); } else if args.is_present("without_bom") { cfg.set_bom_policy(EBomPolicy::EWithoutBom); }
 
 
         cfg.set_recursive(args.is_present("recursive"));
         cfg.set_list_skipped(args.is_present("list_skipped"));
-        let mask = args.value_of("regex");
-        if mask.is_some() { cfg.set_regex(mask.unwrap()); }
+        cfg.set_unordered(args.is_present("unordered"));
+        cfg.set_why(args.is_present("why"));
+        cfg.set_trust_bom(args.is_present("trust_bom"));
+        cfg.set_selection(args)?;
 
-        ListFiles::run(&cfg);
-        Ok(())
+        ListFiles::run(&cfg, Output::new(Some(args)))
     }
 }
 
@@ -290,10 +642,25 @@ struct ListFilesConfig {
     decoder_ref: encoding::EncodingRef,
     bom_policy: EBomPolicy,
     reg: regex::Regex,
+    include: Vec<String>,
+    exclude: Vec<String>,
+    use_ignore_files: bool,
     recursive: bool,
     list_skipped: bool,
+    why: bool,
+    unordered: bool,
+    trust_bom: bool,
+    policy: Option<policy::Policy>,
+    // files without a policy rule are not checked
+    require_rule: bool,
 }
 
+/// Files steering the folder walk and the policy, `convert` leaves them as they are.
+const WALK_CONFIG_FILES: [&str; 3] = [".gitignore", ".ignore", policy::POLICY_FILE];
+
+/// Folders with VCS data, build output and dependencies, skipped unless ignore files are disabled.
+const SKIPPED_FOLDERS: [&str; 3] = [".git", "target", "node_modules"];
+
 impl ListFilesConfig {
     fn new() -> Self {
         Self {
@@ -301,23 +668,73 @@ impl ListFilesConfig {
             decoder_ref: encoding::all::UTF_8 as encoding::EncodingRef,
             bom_policy: EBomPolicy::EIgnore,
             reg: regex::Regex::new(".*").unwrap(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            use_ignore_files: true,
             recursive: false,
             list_skipped: false,
+            why: false,
+            unordered: false,
+            trust_bom: false,
+            policy: None,
+            require_rule: false,
         }
     }
     fn set_folder(&mut self, folder: &str) {
         self.folder = String::from(folder);
     }
-    fn set_decoder(&mut self, decoder: &str) {
-        let decoder_ref = encoding::label::encoding_from_whatwg_label(decoder);
-        if decoder_ref.is_none() {
-            eprintln!("Failed to parse encoding param {}", decoder);
-            return;
-        }
-        self.decoder_ref = decoder_ref.unwrap();
+    fn set_decoder(&mut self, decoder: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.decoder_ref = labels::parse_encoding(decoder)?;
+        Ok(())
+    }
+    fn set_regex(&mut self, mask: &str) -> Result<(), regex::Error> {
+        self.reg = regex::Regex::new(mask)?;
+        Ok(())
+    }
+    fn add_include(&mut self, glob: &str) {
+        self.include.push(String::from(glob));
+    }
+    fn add_exclude(&mut self, glob: &str) {
+        self.exclude.push(String::from(glob));
+    }
+    fn set_use_ignore_files(&mut self, use_ignore_files: bool) {
+        self.use_ignore_files = use_ignore_files;
+    }
+    /// File selection arguments shared by the commands walking a folder.
+    fn add_selection_args<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+        app
+            .arg(
+                clap::Arg::with_name("regex")
+                    .long("regex")
+                    .required(false)
+                    .takes_value(true)
+                    .help("regex matched against the full path, *.rs mask: (.*)+(.\\.rs)$"))
+            .arg(
+                clap::Arg::with_name("include")
+                    .long("include")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .help("only files matching the glob, e.g. *.rs or src/**/*.h, can be repeated"))
+            .arg(
+                clap::Arg::with_name("exclude")
+                    .long("exclude")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .help("skip files and folders matching the glob, can be repeated"))
+            .arg(
+                clap::Arg::with_name("no_ignore")
+                    .long("no_ignore")
+                    .help("do not read .gitignore/.ignore files and do not skip .git, target and node_modules"))
     }
-    fn set_regex(&mut self, mask: &str) {
-        self.reg = regex::Regex::new(mask).unwrap();
+    fn set_selection(&mut self, args: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+        let mask = args.value_of("regex");
+        if mask.is_some() { self.set_regex(mask.unwrap())?; }
+        for glob in args.values_of("include").into_iter().flatten() { self.add_include(glob); }
+        for glob in args.values_of("exclude").into_iter().flatten() { self.add_exclude(glob); }
+        self.set_use_ignore_files(!args.is_present("no_ignore"));
+        Ok(())
     }
     fn set_bom_policy(&mut self, bom_policy: EBomPolicy) {
         self.bom_policy = bom_policy;
@@ -328,11 +745,47 @@ impl ListFilesConfig {
     fn set_list_skipped(&mut self, list_skipped: bool) {
         self.list_skipped = list_skipped;
     }
-    fn create_walker(&self) -> walkdir::WalkDir {
-        let walker = walkdir::WalkDir::new(self.folder.clone()).follow_links(false);
-        if !self.recursive { walker.max_depth(1) } else { walker }
+    fn set_why(&mut self, why: bool) {
+        self.why = why;
+    }
+    fn set_unordered(&mut self, unordered: bool) {
+        self.unordered = unordered;
+    }
+    fn set_trust_bom(&mut self, trust_bom: bool) {
+        self.trust_bom = trust_bom;
+    }
+    fn set_policy(&mut self, policy: Option<policy::Policy>, require_rule: bool) {
+        self.policy = policy;
+        self.require_rule = require_rule;
     }
-    fn filter(&self, entry: &walkdir::DirEntry) -> bool {
+    fn create_walk_builder(&self) -> Result<ignore::WalkBuilder, Box<dyn std::error::Error>> {
+        let mut overrides = ignore::overrides::OverrideBuilder::new(&self.folder);
+        for glob in self.include.iter() { overrides.add(glob)?; }
+        for glob in self.exclude.iter() { overrides.add(&format!("!{}", glob))?; }
+
+        let mut walker = ignore::WalkBuilder::new(&self.folder);
+        walker
+            .standard_filters(false)
+            .git_ignore(self.use_ignore_files)
+            .git_exclude(self.use_ignore_files)
+            .ignore(self.use_ignore_files)
+            .parents(self.use_ignore_files)
+            .require_git(false)
+            .follow_links(false)
+            .overrides(overrides.build()?);
+        if !self.recursive { walker.max_depth(Some(1)); }
+        if self.use_ignore_files {
+            walker.filter_entry(|entry| {
+                let is_dir = entry.file_type().is_some_and(|t| t.is_dir());
+                !(is_dir && entry.depth() > 0 && SKIPPED_FOLDERS.iter().any(|name| entry.file_name() == *name))
+            });
+        }
+        Ok(walker)
+    }
+    fn create_walker(&self) -> Result<ignore::Walk, Box<dyn std::error::Error>> {
+        Ok(self.create_walk_builder()?.build())
+    }
+    fn filter(&self, entry: &ignore::DirEntry) -> bool {
         let meta = std::fs::metadata(entry.path());
         if meta.is_err() {
             eprintln!("{}", meta.err().unwrap());
@@ -345,37 +798,146 @@ impl ListFilesConfig {
 
         return false;
     }
-    fn check_encoding(&self, filepath: &str) -> std::io::Result<bool> {
-        detect::is_file_has_same_encoding(filepath, &self.decoder_ref, &self.bom_policy)
+    fn rule_for(&self, filepath: &str) -> Option<&policy::Rule> {
+        self.policy.as_ref().and_then(|p| p.rule_for(filepath))
+    }
+    /// Encoding a file is expected to be in when a policy rule or an `--include` glob selects it by name.
+    fn expected_encoding(&self, filepath: &str) -> Option<EncodingRef> {
+        let rule = self.rule_for(filepath);
+        if let Some(rule) = rule { return Some(rule.encoding); }
+        if !self.include.is_empty() && !self.require_rule { Some(self.decoder_ref) } else { None }
+    }
+    fn check_encoding(&self, filepath: &str) -> std::io::Result<detect::EFileCheck> {
+        let rule = self.rule_for(filepath);
+        if let Some(rule) = rule { return policy::check_file(filepath, rule); }
+        if self.require_rule { return Ok(detect::EFileCheck::ENoRule); }
+        detect::check_file_encoding(filepath, &self.decoder_ref, &self.bom_policy, self.trust_bom)
     }
 }
 
-impl ListFiles {
-    fn run(cfg: &ListFilesConfig) {
-        let walker = cfg.create_walker();
-
-        for entry in walker {
-            if entry.is_err() {
-                eprintln!("{}", entry.err().unwrap());
-                continue;
-            }
-
-            let entry = entry.unwrap();
-            if cfg.filter(&entry) { continue; }
-
-            let filepath = entry.file_name().to_str().unwrap();
+/// Result of checking one file, `Err` holds a read error message.
+type ListResult = Result<detect::EFileCheck, String>;
 
-            let result = cfg.check_encoding(entry.path().to_str().unwrap());
-            if result.is_err() {
-                eprintln!("Failed to read file {}. Error: {}", filepath, result.err().unwrap());
-                continue;
-            }
+impl ListFiles {
+    fn check_file(cfg: &ListFilesConfig, entry: &ignore::DirEntry) -> ListResult {
+        let result = cfg.check_encoding(entry.path().to_str().unwrap());
+        if result.is_err() {
+            return Err(format!("Failed to read file {}. Error: {}", entry.file_name().to_str().unwrap(), result.err().unwrap()));
+        }
+        Ok(result.unwrap())
+    }
+    /// Checks files on the walker threads, `handle` is called on a separate thread
+    /// for every result as it arrives or, unless `cfg.unordered` is set, in path order at the end.
+    fn walk<H>(cfg: &ListFilesConfig, handle: H) -> Result<(), Box<dyn std::error::Error>>
+        where H: FnMut(&str, &ListResult) + Send {
+        Self::walk_with(cfg, |entry| Self::check_file(cfg, entry), handle)
+    }
+    /// Path the walker failed on and the error without it, the walked folder if the error has no path.
+    fn split_walk_error<T>(cfg: &ListFilesConfig, err: ignore::Error) -> (String, Result<T, String>) {
+        match err {
+            ignore::Error::WithPath { path, err } => (path.display().to_string(), Err(err.to_string())),
+            ignore::Error::WithDepth { err, .. } | ignore::Error::WithLineNumber { err, .. } => Self::split_walk_error(cfg, *err),
+            ignore::Error::Loop { ancestor, child } =>
+                (child.display().to_string(), Err(format!("file system loop found, it points to {}", ancestor.display()))),
+            err => (cfg.folder.clone(), Err(err.to_string())),
+        }
+    }
+    /// Same as `walk` with `process` instead of the encoding check, `Err` holds an error message.
+    fn walk_with<T, F, H>(cfg: &ListFilesConfig, process: F, mut handle: H) -> Result<(), Box<dyn std::error::Error>>
+        where T: Send, F: Fn(&ignore::DirEntry) -> Result<T, String> + Sync, H: FnMut(&str, &Result<T, String>) + Send {
+        let walker = cfg.create_walk_builder()?.build_parallel();
+        let (sender, receiver) = std::sync::mpsc::channel::<(String, Result<T, String>)>();
+        let unordered = cfg.unordered;
+        std::thread::scope(|scope| {
+            let printer = scope.spawn(move || {
+                let mut results = Vec::new();
+                for (path, result) in receiver {
+                    if unordered { handle(&path, &result); } else { results.push((path, result)); }
+                }
+                results.sort_by(|a, b| a.0.cmp(&b.0));
+                for (path, result) in results.iter() {
+                    handle(path, result);
+                }
+            });
+
+            let process = &process;
+            walker.run(|| {
+                let sender = sender.clone();
+                Box::new(move |entry| {
+                    if entry.is_err() {
+                        let _ = sender.send(Self::split_walk_error(cfg, entry.err().unwrap()));
+                        return ignore::WalkState::Continue;
+                    }
 
-            if result.unwrap() {
-                println!("{}", entry.path().display());
-            } else if cfg.list_skipped {
-                println!("skipped file: {}", entry.path().display());
+                    let entry = entry.unwrap();
+                    if cfg.filter(&entry) { return ignore::WalkState::Continue; }
+
+                    let result = process(&entry);
+                    let _ = sender.send((entry.path().display().to_string(), result));
+                    ignore::WalkState::Continue
+                })
+            });
+            drop(sender);
+            printer.join().unwrap();
+        });
+        Ok(())
+    }
+    fn record(path: &str, status: &str, reason: Option<String>) -> Record {
+        Record::new().field("path", path).field("status", status).field("reason", reason)
+    }
+    fn run(cfg: &ListFilesConfig, mut out: Output) -> Result<(), Box<dyn std::error::Error>> {
+        let mut binary = 0;
+        Self::walk(cfg, |path, result| {
+            match result {
+                Ok(detect::EFileCheck::EMatch) => out.record(Self::record(path, "match", None).text(path)),
+                Ok(check @ detect::EFileCheck::EBomMismatch(..)) if !cfg.trust_bom => {
+                    // the file decodes, but its BOM says it is in another encoding
+                    eprintln!("warning: {}: {}", path, check.reason().unwrap());
+                    out.record(Self::record(path, "match", check.reason()).text(path));
+                }
+                Ok(detect::EFileCheck::EBinary) => {
+                    binary += 1;
+                    if cfg.list_skipped { out.record(Self::record(path, "binary", None).text(format!("binary file: {}", path))) }
+                }
+                Ok(check) => {
+                    let reason = check.reason();
+                    if let (true, Some(why)) = (cfg.why, reason.as_ref()) {
+                        let text = format!("skipped file: {} ({})", path, why);
+                        out.record(Self::record(path, "skipped", reason).text(text));
+                    } else if cfg.list_skipped {
+                        out.record(Self::record(path, "skipped", None).text(format!("skipped file: {}", path)));
+                    }
+                }
+                Err(msg) => eprintln!("{}", msg),
             }
-        }
+        })?;
+        drop(out);
+        if binary > 0 { eprintln!("Binary files skipped: {}", binary); }
+        Ok(())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use encoding::types::{DecoderTrap, EncoderTrap, Encoding};
+
+    use super::{ConvertCmd, ConvertConfig};
+
+    #[test]
+    fn converts_detected_cp1251_without_force() {
+        let text = "// Модуль чтения конфигурации. Максимальный размер буфера задаётся ниже.\n\
+            int main(void) { return 0; } // Мы открываем файл и читаем его построчно\n";
+        let folder = std::env::temp_dir().join(format!("wtool-convert-{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+        let src = folder.join("source.c");
+        let tgt = folder.join("target.c");
+        std::fs::write(&src, encoding::all::WINDOWS_1251.encode(text, EncoderTrap::Strict).unwrap()).unwrap();
+
+        let cfg = ConvertConfig::new(encoding::all::UTF_8);
+        let result = ConvertCmd::convert_file(src.to_str().unwrap(), tgt.to_str().unwrap(), &cfg);
+        let converted = std::fs::read(&tgt);
+        std::fs::remove_dir_all(&folder).unwrap();
+
+        assert!(result.unwrap());
+        assert_eq!(encoding::all::UTF_8.decode(&converted.unwrap(), DecoderTrap::Strict).unwrap(), text);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,215 @@
+use crate::common;
+use crate::common::output::{Output, Record};
+
+use super::backup;
+use super::detect;
+use super::detector;
+use super::encoding::EncodingRef;
+use super::labels;
+use super::ConvertCmd;
+
+/// Candidates are scored on this many characters from the start of the text.
+const SAMPLE_CHARS: usize = 4096;
+
+/// Best chains kept after each step when chains are longer than one step.
+const BEAM_WIDTH: usize = 10;
+
+/// Characters of the first changed line shown for a candidate.
+const PREVIEW_LEN: usize = 60;
+
+/// Text decoded from bytes in `right` as if they were in `wrong`, undone by encoding to `wrong` and decoding as `right`.
+#[derive(Clone, Copy)]
+struct Step {
+    wrong: EncodingRef,
+    right: EncodingRef,
+}
+
+impl Step {
+    fn apply(&self, text: &str) -> Option<String> {
+        let data = self.wrong.encode(text, encoding::types::EncoderTrap::Strict).ok()?;
+        self.right.decode(&data, encoding::types::DecoderTrap::Strict).ok()
+    }
+}
+
+#[derive(Clone)]
+struct Candidate {
+    steps: Vec<Step>,
+    text: String,
+    score: f32,
+}
+
+impl Candidate {
+    fn describe(&self) -> String {
+        let steps: Vec<String> = self.steps.iter()
+            .map(|s| format!("{} read as {}", s.right.name(), s.wrong.name()))
+            .collect();
+        steps.join(", then ")
+    }
+    fn apply(&self, text: &str) -> Option<String> {
+        let mut text = String::from(text);
+        for step in self.steps.iter() {
+            text = step.apply(&text)?;
+        }
+        Some(text)
+    }
+}
+
+pub struct MojibakeCmd;
+
+impl MojibakeCmd {
+    /// Undoes up to `depth` wrong decodings of the sample, the candidates are ordered from the most plausible text.
+    fn find_candidates(sample: &str, depth: usize) -> Vec<Candidate> {
+        let codecs = detector::candidates();
+        let mut found = Vec::<Candidate>::new();
+        let mut current = vec![Candidate { steps: Vec::new(), text: String::from(sample), score: 0.0 }];
+        for _ in 0..depth {
+            let mut next = Vec::new();
+            for candidate in current.iter() {
+                for wrong in codecs.iter() {
+                    for right in codecs.iter() {
+                        if wrong.name() == right.name() { continue; }
+                        let step = Step { wrong: *wrong, right: *right };
+                        let text = step.apply(&candidate.text);
+                        if text.is_none() || text.as_ref().unwrap() == &candidate.text { continue; }
+                        let text = text.unwrap();
+                        // both encodings of a real mix-up agree on ASCII, so it survives the fix unchanged
+                        if !text.chars().filter(char::is_ascii).eq(candidate.text.chars().filter(char::is_ascii)) { continue; }
+                        if found.iter().chain(next.iter()).any(|c: &Candidate| c.text == text) { continue; }
+
+                        let mut steps = candidate.steps.clone();
+                        steps.push(step);
+                        let score = detector::score_text(&text);
+                        next.push(Candidate { steps, text, score });
+                    }
+                }
+            }
+            next.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+            found.extend(next.iter().cloned());
+            next.truncate(BEAM_WIDTH);
+            current = next;
+        }
+        // shorter chains win ties, they come first
+        found.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+        found
+    }
+    fn preview(original: &str, fixed: &str) -> String {
+        let line = original.lines().zip(fixed.lines())
+            .find(|(a, b)| a != b)
+            .map_or(fixed.lines().next().unwrap_or(""), |(_, b)| b);
+        line.chars().take(PREVIEW_LEN).collect()
+    }
+}
+
+impl common::Command for MojibakeCmd {
+    fn create() -> Box<Self> { Box::<Self>::new(Self {}) }
+    fn name() -> &'static str { "fix_mojibake" }
+    fn fill_subcommand<'a, 'b>(&self, app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+        app.subcommand(
+            clap::App::new(Self::name())
+                .alias("fix-mojibake")
+                .about("finds which wrong decodings garbled a file, e.g. utf-8 read as windows-1252, and undoes them")
+                .arg(clap::Arg::with_name("filepath").required(true))
+                .arg(
+                    clap::Arg::with_name("encoding")
+                        .long("encoding")
+                        .takes_value(true)
+                        .help("file encoding, detected if omitted"))
+                .arg(
+                    clap::Arg::with_name("depth")
+                        .long("depth")
+                        .takes_value(true)
+                        .default_value("1")
+                        .possible_values(&["1", "2"])
+                        .help("number of wrong decodings to undo, 2 is for text garbled twice"))
+                .arg(
+                    clap::Arg::with_name("count")
+                        .long("count")
+                        .short("n")
+                        .takes_value(true)
+                        .default_value("5")
+                        .help("number of candidates to show"))
+                .arg(
+                    clap::Arg::with_name("apply")
+                        .long("apply")
+                        .help("rewrite the file with the best candidate"))
+                .arg(
+                    clap::Arg::with_name("candidate")
+                        .long("candidate")
+                        .takes_value(true)
+                        .requires("apply")
+                        .help("number of the candidate to apply instead of the best one"))
+                .arg(
+                    clap::Arg::with_name("backup")
+                        .long("backup")
+                        .requires("apply")
+                        .help("keep a .bak copy of the file, 'encoding restore' rolls it back"))
+        )
+    }
+    fn run(&self, args: Option<&clap::ArgMatches>) -> Result<(), Box<dyn std::error::Error>> {
+        let args = args.unwrap();
+        let path = args.value_of("filepath").unwrap();
+        let depth = args.value_of("depth").unwrap().parse::<usize>()?;
+        let count = args.value_of("count").unwrap().parse::<usize>()?;
+
+        let data = detect::read_file(path)?;
+        let (e, bom_len) = match args.value_of("encoding") {
+            Some(label) => {
+                let e = labels::parse_encoding(label)?;
+                (e, detect::bom_len(&data, &e))
+            }
+            None => ConvertCmd::detect_decoder(&data, true, true)?,
+        };
+        let text = match e.decode(&data[bom_len..], encoding::types::DecoderTrap::Strict) {
+            Ok(text) => text,
+            Err(err) => return Err(common::errors::ErrorString::new(format!("{} is not valid {}: {}", path, e.name(), err))),
+        };
+        let sample: String = text.chars().take(SAMPLE_CHARS).collect();
+
+        let baseline = detector::score_text(&sample);
+        let candidates: Vec<Candidate> = Self::find_candidates(&sample, depth).into_iter()
+            .filter(|c| c.score > baseline)
+            .take(count)
+            .collect();
+        let mut out = Output::new(Some(args));
+        out.text(format!("{} ({}), score {:.1}%: {}", path, e.name(), baseline * 100.0, Self::preview("", &sample)));
+        if candidates.is_empty() {
+            out.text("No candidate looks more plausible than the current text");
+            return Ok(());
+        }
+        for (i, candidate) in candidates.iter().enumerate() {
+            let preview = Self::preview(&sample, &candidate.text);
+            out.record(Record::new()
+                .field("candidate", i + 1)
+                .field("score", candidate.score)
+                .field("baseline", baseline)
+                .field("fix", candidate.describe())
+                .field("preview", &preview)
+                .text(format!("{:>3}. {:5.1}% {}: {}", i + 1, candidate.score * 100.0, candidate.describe(), preview)));
+        }
+        if !args.is_present("apply") { return Ok(()); }
+
+        let chosen = args.value_of("candidate").unwrap_or("1").parse::<usize>()?;
+        if chosen == 0 || chosen > candidates.len() {
+            return Err(common::errors::ErrorString::new(format!("There is no candidate {}", chosen)));
+        }
+        let candidate = &candidates[chosen - 1];
+        let fixed = candidate.apply(&text);
+        let fixed = match fixed {
+            Some(fixed) => fixed,
+            None => return Err(common::errors::ErrorString::new(format!(
+                "Candidate {} fits the start of the file, but not the whole file", chosen))),
+        };
+        let encoded = match e.encode(&fixed, encoding::types::EncoderTrap::Strict) {
+            Ok(encoded) => encoded,
+            Err(err) => return Err(common::errors::ErrorString::new(format!(
+                "The fixed text can not be saved as {}: {}", e.name(), err))),
+        };
+        let mut result = data[..bom_len].to_vec();
+        result.extend(encoded);
+
+        let journal = if args.is_present("backup") { Some(backup::Journal::create()?) } else { None };
+        backup::write_file(std::path::Path::new(path), &result, journal.as_ref())?;
+        out.text(format!("Fixed {}: {}", path, candidate.describe()));
+        Ok(())
+    }
+}
@@ -0,0 +1,251 @@
+use unicode_normalization::UnicodeNormalization;
+
+use crate::common;
+use crate::common::output::{Output, Record};
+
+use super::backup;
+use super::detect;
+use super::encoding::EncodingRef;
+use super::labels;
+use super::stream;
+use super::ConvertCmd;
+use super::ListFilesConfig;
+
+#[allow(clippy::enum_variant_names)]
+enum EForm {
+    ENfc,
+    ENfd,
+    ENfkc,
+    ENfkd,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+#[allow(clippy::enum_variant_names)]
+enum EScript {
+    ELatin,
+    EGreek,
+    ECyrillic,
+}
+
+impl EScript {
+    fn of(c: char) -> Option<Self> {
+        match c as u32 {
+            0x41..=0x5A | 0x61..=0x7A | 0xC0..=0x24F | 0x1E00..=0x1EFF => Some(EScript::ELatin),
+            0x370..=0x3FF | 0x1F00..=0x1FFF => Some(EScript::EGreek),
+            0x400..=0x52F => Some(EScript::ECyrillic),
+            _ => None,
+        }
+    }
+    fn name(&self) -> &'static str {
+        match self {
+            EScript::ELatin => "Latin",
+            EScript::EGreek => "Greek",
+            EScript::ECyrillic => "Cyrillic",
+        }
+    }
+}
+
+/// Word made of letters from more than one script, e.g. Latin with a Cyrillic lookalike.
+struct MixedToken {
+    line: usize,
+    column: usize,
+    token: String,
+    // script of most letters and the letters from other scripts
+    script: EScript,
+    others: Vec<(char, EScript)>,
+}
+
+pub struct NormalizeCmd;
+
+impl NormalizeCmd {
+    fn normalize(text: &str, form: &EForm) -> String {
+        match form {
+            EForm::ENfc => text.nfc().collect(),
+            EForm::ENfd => text.nfd().collect(),
+            EForm::ENfkc => text.nfkc().collect(),
+            EForm::ENfkd => text.nfkd().collect(),
+        }
+    }
+    fn flush_token(token: &mut String, line: usize, column: usize, result: &mut Vec<MixedToken>) {
+        let letters: Vec<(char, EScript)> = token.chars().filter_map(|c| EScript::of(c).map(|s| (c, s))).collect();
+        let count = |script: EScript| letters.iter().filter(|(_, s)| *s == script).count();
+        // max_by_key keeps the last maximum, so on a tie the script seen first in the token wins
+        let script = letters.iter().rev().map(|(_, s)| *s).max_by_key(|s| count(*s));
+        if let Some(script) = script {
+            let others: Vec<_> = letters.iter().filter(|(_, s)| *s != script).cloned().collect();
+            if !others.is_empty() {
+                result.push(MixedToken { line, column, token: token.clone(), script, others });
+            }
+        }
+        token.clear();
+    }
+    fn find_mixed_tokens(text: &str) -> Vec<MixedToken> {
+        let mut result = Vec::new();
+        let mut token = String::new();
+        let (mut line, mut column) = (1, 1);
+        let mut token_column = 1;
+        for c in text.chars() {
+            if c.is_alphanumeric() || c == '_' {
+                if token.is_empty() { token_column = column; }
+                token.push(c);
+            } else if !token.is_empty() {
+                Self::flush_token(&mut token, line, token_column, &mut result);
+            }
+            if c == '\n' {
+                line += 1;
+                column = 1;
+            } else {
+                column += 1;
+            }
+        }
+        Self::flush_token(&mut token, line, token_column, &mut result);
+        result
+    }
+    fn decode(data: &[u8], e: EncodingRef) -> Result<String, Box<dyn std::error::Error>> {
+        let mut decoder = stream::StreamDecoder::new(e, encoding::types::DecoderTrap::Strict, 0);
+        let mut text = String::new();
+        let mut issues = Vec::new();
+        decoder.feed(data, &mut text, &mut issues)?;
+        decoder.finish(&mut text, &mut issues)?;
+        Ok(text)
+    }
+    fn encode(text: &str, e: EncodingRef) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let mut encoder = stream::StreamEncoder::new(e, encoding::types::EncoderTrap::Strict);
+        let mut data = Vec::new();
+        let mut issues = Vec::new();
+        encoder.feed(text, &mut data, &mut issues)?;
+        encoder.finish(&mut data, &mut issues)?;
+        Ok(data)
+    }
+    fn record(path: &str, kind: &str, position: Option<(usize, usize)>, message: &str) -> Record {
+        Record::new()
+            .field("path", path)
+            .field("kind", kind)
+            .field("line", position.map(|p| p.0))
+            .field("column", position.map(|p| p.1))
+            .field("message", message)
+    }
+    /// Reports mixed-script tokens and returns the normalized file content or `None` if it is already normalized.
+    fn process(out: &mut Output, path: &str, decoder_ref: Option<EncodingRef>, form: Option<&EForm>) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        let data = detect::read_file(path)?;
+        let (e, bom_len) =
+            match decoder_ref { Some(e) => (e, detect::bom_len(&data, &e)), None => ConvertCmd::detect_decoder(&data, true, false)? };
+        let text = Self::decode(&data[bom_len..], e)?;
+
+        for mixed in Self::find_mixed_tokens(&text) {
+            let others: Vec<String> = mixed.others.iter()
+                .map(|(c, s)| format!("{} '{}' U+{:04X}", s.name(), c, *c as u32))
+                .collect();
+            let message = format!("mixed-script token \"{}\", {} with {}", mixed.token, mixed.script.name(), others.join(", "));
+            out.record(Self::record(path, "mixed_script", Some((mixed.line, mixed.column)), &message)
+                .text(format!("{}:{}:{}: warning: {}", path, mixed.line, mixed.column, message)));
+        }
+
+        if form.is_none() { return Ok(None); }
+        let normalized = Self::normalize(&text, form.unwrap());
+        if normalized == text { return Ok(None); }
+        let mut result = data[..bom_len].to_vec();
+        result.extend(Self::encode(&normalized, e)?);
+        Ok(Some(result))
+    }
+}
+
+impl common::Command for NormalizeCmd {
+    fn create() -> Box<Self> { Box::<Self>::new(Self {}) }
+    fn name() -> &'static str { "normalize" }
+    fn fill_subcommand<'a, 'b>(&self, app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+        app.subcommand(
+            ListFilesConfig::add_selection_args(clap::App::new(Self::name()))
+                .about("reports mixed-script words and converts text to a Unicode normalization form")
+                .arg(clap::Arg::with_name("path").required(true))
+                .arg(
+                    clap::Arg::with_name("form")
+                        .long("form")
+                        .takes_value(true)
+                        .possible_values(&["nfc", "nfd", "nfkc", "nfkd"])
+                        .help("normalization form to convert to, otherwise only report mixed-script words"))
+                .arg(
+                    clap::Arg::with_name("encoding")
+                        .long("encoding")
+                        .takes_value(true)
+                        .help("file encoding, detected if omitted"))
+                .arg(
+                    clap::Arg::with_name("recursive")
+                        .long("recursive")
+                        .short("r"))
+                .arg(
+                    clap::Arg::with_name("dry_run")
+                        .long("dry_run")
+                        .help("only print files that would be changed"))
+        )
+    }
+    fn run(&self, args: Option<&clap::ArgMatches>) -> Result<(), Box<dyn std::error::Error>> {
+        let args = args.unwrap();
+        let form = match args.value_of("form") {
+            Some("nfc") => Some(EForm::ENfc),
+            Some("nfd") => Some(EForm::ENfd),
+            Some("nfkc") => Some(EForm::ENfkc),
+            Some(_) => Some(EForm::ENfkd),
+            None => None,
+        };
+        let decoder_ref = match args.value_of("encoding") {
+            Some(label) => Some(labels::parse_encoding(label)?),
+            None => None,
+        };
+        let dry_run = args.is_present("dry_run");
+
+        let mut cfg = ListFilesConfig::new();
+        cfg.set_folder(args.value_of("path").unwrap());
+        cfg.set_recursive(args.is_present("recursive"));
+        cfg.set_selection(args)?;
+
+        let mut out = Output::new(Some(args));
+        let mut changed = 0;
+        let mut failed = 0;
+        for entry in cfg.create_walker()? {
+            if entry.is_err() {
+                eprintln!("{}", entry.err().unwrap());
+                failed += 1;
+                continue;
+            }
+
+            let entry = entry.unwrap();
+            if cfg.filter(&entry) { continue; }
+
+            let path = entry.path();
+            let is_wide = decoder_ref.is_some_and(|e| detect::is_wide(&e));
+            // a file that can not be read fails in process
+            if !is_wide && detect::is_binary_file(path.to_str().unwrap()).unwrap_or(false) { continue; }
+
+            let result = Self::process(&mut out, path.to_str().unwrap(), decoder_ref, form.as_ref());
+            if result.is_err() {
+                eprintln!("Failed to process file {}. Error: {}", path.display(), result.err().unwrap());
+                failed += 1;
+                continue;
+            }
+
+            let result = result.unwrap();
+            if result.is_none() { continue; }
+            changed += 1;
+            let status = if dry_run { "would normalize" } else { "normalized" };
+            let form = args.value_of("form").unwrap().to_uppercase();
+            out.record(Self::record(path.to_str().unwrap(), status, None, &form)
+                .text(format!("{} to {}: {}", status, form, path.display())));
+            if dry_run { continue; }
+
+            let write_result = backup::write_file(path, &result.unwrap(), None);
+            if write_result.is_err() {
+                eprintln!("Failed to write file {}. Error: {}", path.display(), write_result.err().unwrap());
+                failed += 1;
+            }
+        }
+
+        if form.is_some() {
+            out.text(format!("{}: {}, Failed: {}", if dry_run { "To change" } else { "Changed" }, changed, failed));
+        }
+        if failed > 0 {
+            return Err(common::errors::ErrorString::new(format!("Failed to process {} file(s)", failed)));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+use std::path::{Path, PathBuf};
+
+use crate::common;
+
+use super::detect;
+use super::detect::{EBomPolicy, EFileCheck};
+use super::encoding::EncodingRef;
+use super::eol;
+use super::labels;
+use super::eol::EEol;
+
+/// Policy file looked up in the walked folder and its parents.
+///
+/// ```toml
+/// [[rule]]
+/// glob = "*.rs"
+/// encoding = "utf-8"
+/// bom = false
+/// eol = "lf"
+///
+/// [[rule]]
+/// glob = "*.bat"
+/// encoding = "cp866"
+/// eol = "crlf"
+/// ```
+///
+/// `bom` and `eol` may be omitted to accept any. Globs without a `/` match the file name,
+/// other globs match the path relative to the policy file. The last matching rule wins.
+pub(super) const POLICY_FILE: &str = ".wtool-encoding.toml";
+
+pub(super) struct Rule {
+    glob: globset::GlobMatcher,
+    pub(super) encoding: EncodingRef,
+    pub(super) bom: EBomPolicy,
+    pub(super) eol: Option<EEol>,
+}
+
+pub(super) struct Policy {
+    root: PathBuf,
+    rules: Vec<Rule>,
+}
+
+impl Rule {
+    fn parse(value: &toml::Value) -> Result<Self, Box<dyn std::error::Error>> {
+        let field = |name: &str| value.get(name);
+        let glob = field("glob").and_then(|v| v.as_str());
+        if glob.is_none() {
+            return Err(common::errors::ErrorStr::new("Policy rule has no glob"));
+        }
+        let glob = glob.unwrap();
+        let encoding = field("encoding").and_then(|v| v.as_str());
+        if encoding.is_none() {
+            return Err(common::errors::ErrorString::new(format!("Policy rule {} has no encoding", glob)));
+        }
+        let encoding_ref = labels::encoding_from_label(encoding.unwrap());
+        if encoding_ref.is_none() {
+            return Err(common::errors::ErrorString::new(format!("Policy rule {} has unknown encoding {}", glob, encoding.unwrap())));
+        }
+        let bom = match field("bom").map(|v| v.as_bool()) {
+            None => EBomPolicy::EIgnore,
+            Some(Some(true)) => EBomPolicy::EWithBom,
+            Some(Some(false)) => EBomPolicy::EWithoutBom,
+            Some(None) => return Err(common::errors::ErrorString::new(format!("Policy rule {}: bom must be true or false", glob))),
+        };
+        let eol = match field("eol").map(|v| v.as_str()) {
+            None => None,
+            Some(Some("lf")) => Some(EEol::ELf),
+            Some(Some("crlf")) => Some(EEol::ECrLf),
+            Some(_) => return Err(common::errors::ErrorString::new(format!("Policy rule {}: eol must be lf or crlf", glob))),
+        };
+
+        let pattern = glob.trim_start_matches('/');
+        let glob_matcher = globset::GlobBuilder::new(pattern).literal_separator(true).build()?.compile_matcher();
+        Ok(Self { glob: glob_matcher, encoding: encoding_ref.unwrap(), bom, eol })
+    }
+    fn is_match(&self, relative_path: &Path) -> bool {
+        if self.glob.glob().glob().contains('/') { return self.glob.is_match(relative_path); }
+        relative_path.file_name().is_some_and(|name| self.glob.is_match(name))
+    }
+}
+
+impl Policy {
+    pub(super) fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let text = std::fs::read_to_string(path)?;
+        let value = text.parse::<toml::Value>();
+        if value.is_err() {
+            return Err(common::errors::ErrorString::new(format!("Failed to parse {}. Error: {}", path.display(), value.err().unwrap())));
+        }
+
+        let value = value.unwrap();
+        let mut rules = Vec::new();
+        for rule in value.get("rule").and_then(|v| v.as_array()).map_or(&[][..], |v| &v[..]) {
+            rules.push(Rule::parse(rule)?);
+        }
+        let root = std::fs::canonicalize(path)?.parent().unwrap().to_path_buf();
+        Ok(Self { root, rules })
+    }
+    /// Loads the policy file of `folder` or of its closest parent that has one.
+    pub(super) fn find(folder: &Path) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let folder = std::fs::canonicalize(folder)?;
+        for dir in folder.ancestors() {
+            let path = dir.join(POLICY_FILE);
+            if path.is_file() { return Ok(Some(Self::load(&path)?)); }
+        }
+        Ok(None)
+    }
+    /// Explicit policy file if given, otherwise the one found for `folder`.
+    pub(super) fn open(path: Option<&str>, folder: &str) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        if let Some(path) = path { return Ok(Some(Self::load(Path::new(path))?)); }
+        Self::find(Path::new(folder))
+    }
+    pub(super) fn rule_for(&self, path: &str) -> Option<&Rule> {
+        let path = std::fs::canonicalize(path);
+        if path.is_err() { return None; }
+        let path = path.unwrap();
+        let relative = path.strip_prefix(&self.root).unwrap_or(&path);
+        self.rules.iter().rev().find(|rule| rule.is_match(relative))
+    }
+}
+
+/// Checks encoding and BOM, then line endings of a file against a rule.
+pub(super) fn check_file(filepath: &str, rule: &Rule) -> std::io::Result<EFileCheck> {
+    let result = detect::check_file_encoding(filepath, &rule.encoding, &rule.bom, false)?;
+    match (&result, &rule.eol) {
+        (EFileCheck::EMatch, Some(expected)) => {
+            let style = eol::file_line_ending_style(filepath, &rule.encoding)?;
+            if style == "none" || style == expected.name() { return Ok(result); }
+            Ok(EFileCheck::EWrongEol(style, expected.name()))
+        }
+        _ => Ok(result),
+    }
+}
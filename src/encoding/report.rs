@@ -0,0 +1,196 @@
+use std::collections::BTreeMap;
+
+use crate::common;
+use crate::common::output::{EFormat, Output, Record};
+
+use super::detect;
+use super::detector;
+use super::eol;
+use super::stream;
+use super::ListFilesConfig;
+
+/// Classification of a single file, text files are classified by their first `detector::SAMPLE_SIZE` bytes.
+struct FileRecord {
+    path: String,
+    extension: String,
+    size: u64,
+    binary: bool,
+    encoding: String,
+    confidence: f32,
+    ambiguous: bool,
+    bom: bool,
+    eol: &'static str,
+}
+
+impl FileRecord {
+    fn classify(path: &std::path::Path) -> std::io::Result<Self> {
+        let mut file = std::fs::File::open(path)?;
+        let size = file.metadata()?.len();
+        let sample = stream::read_sample(&mut file, detector::SAMPLE_SIZE)?;
+
+        let mut record = Self {
+            path: path.display().to_string(),
+            extension: path.extension().map(|e| e.to_string_lossy().to_lowercase()).unwrap_or_default(),
+            size,
+            binary: detect::is_binary(&sample),
+            encoding: String::from("binary"),
+            confidence: 0.0,
+            ambiguous: false,
+            bom: false,
+            eol: "none",
+        };
+        if record.binary { return Ok(record); }
+
+        let ranked = detector::detect_encoding(&sample, sample.len() as u64 == size);
+        if ranked.is_empty() {
+            record.encoding = String::from("unknown");
+        } else {
+            record.encoding = String::from(ranked[0].encoding.name());
+            record.confidence = ranked[0].confidence;
+            record.ambiguous = detector::is_ambiguous(&ranked);
+            record.bom = ranked[0].bom_len > 0;
+        }
+        record.eol = eol::line_ending_style(&sample);
+        Ok(record)
+    }
+}
+
+#[derive(Default)]
+struct Counter {
+    files: usize,
+    bytes: u64,
+}
+
+pub struct ReportCmd;
+
+impl ReportCmd {
+    fn print_summary(out: &mut Output, title: &str, groups: &BTreeMap<String, Counter>) {
+        out.text(format!("{:<24} {:>8} {:>14}", title, "files", "bytes"));
+        let mut groups: Vec<_> = groups.iter().collect();
+        groups.sort_by(|a, b| b.1.files.cmp(&a.1.files).then(a.0.cmp(b.0)));
+        for (name, counter) in groups {
+            out.record(Record::new()
+                .field("group", title)
+                .field("name", name)
+                .field("files", counter.files)
+                .field("bytes", counter.bytes)
+                .text(format!("{:<24} {:>8} {:>14}", name, counter.files, counter.bytes)));
+        }
+        out.text("");
+    }
+    fn print_list(out: &mut Output, records: &[FileRecord]) {
+        for r in records {
+            let encoding = if r.bom { format!("{} (bom)", r.encoding) } else { r.encoding.clone() };
+            out.record(Record::new()
+                .field("path", &r.path)
+                .field("extension", &r.extension)
+                .field("size", r.size)
+                .field("binary", r.binary)
+                .field("encoding", &r.encoding)
+                .field("confidence", r.confidence)
+                .field("ambiguous", r.ambiguous)
+                .field("bom", r.bom)
+                .field("eol", r.eol)
+                .text(format!("{:<24} {:<6} {:>14} {}", encoding, r.eol, r.size, r.path)));
+        }
+    }
+}
+
+impl common::Command for ReportCmd {
+    fn create() -> Box<Self> { Box::<Self>::new(Self {}) }
+    fn name() -> &'static str { "report" }
+    fn fill_subcommand<'a, 'b>(&self, app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+        app.subcommand(
+            ListFilesConfig::add_selection_args(clap::App::new(Self::name()))
+                .about("summary of file encodings, BOMs and line endings in a folder")
+                .arg(clap::Arg::with_name("folder").required(true))
+                .arg(
+                    clap::Arg::with_name("recursive")
+                        .long("recursive")
+                        .short("r")
+                        .takes_value(false)
+                        .required(false))
+                .arg(
+                    clap::Arg::with_name("list")
+                        .long("list")
+                        .takes_value(false)
+                        .required(false)
+                        .help("list every file instead of the summary, in the --format format"))
+                .arg(
+                    clap::Arg::with_name("list_path")
+                        .long("list_path")
+                        .takes_value(true)
+                        .requires("list")
+                        .required(false)
+                        .help("write the file list here and print the summary to stdout"))
+        )
+    }
+    fn run(&self, args: Option<&clap::ArgMatches>) -> Result<(), Box<dyn std::error::Error>> {
+        let args = args.unwrap();
+        let mut cfg = ListFilesConfig::new();
+        cfg.set_folder(args.value_of("folder").unwrap());
+        cfg.set_recursive(args.is_present("recursive"));
+        cfg.set_selection(args)?;
+
+        let mut records = Vec::<FileRecord>::new();
+        for entry in cfg.create_walker()? {
+            if entry.is_err() {
+                eprintln!("{}", entry.err().unwrap());
+                continue;
+            }
+
+            let entry = entry.unwrap();
+            if cfg.filter(&entry) { continue; }
+
+            let record = FileRecord::classify(entry.path());
+            if record.is_err() {
+                eprintln!("Failed to read file {}. Error: {}", entry.path().display(), record.err().unwrap());
+                continue;
+            }
+            records.push(record.unwrap());
+        }
+
+        let mut by_encoding = BTreeMap::<String, Counter>::new();
+        let mut by_extension = BTreeMap::<String, Counter>::new();
+        let mut by_eol = BTreeMap::<String, Counter>::new();
+        let (mut with_bom, mut ambiguous) = (0, 0);
+        for r in records.iter() {
+            let encoding = if r.bom { format!("{} (bom)", r.encoding) } else { r.encoding.clone() };
+            let extension = if r.extension.is_empty() { String::from("<none>") } else { format!(".{}", r.extension) };
+            for (groups, key) in [(&mut by_encoding, encoding), (&mut by_extension, extension)] {
+                let counter = groups.entry(key).or_default();
+                counter.files += 1;
+                counter.bytes += r.size;
+            }
+            if !r.binary {
+                let counter = by_eol.entry(String::from(r.eol)).or_default();
+                counter.files += 1;
+                counter.bytes += r.size;
+            }
+            if r.bom { with_bom += 1; }
+            if r.ambiguous { ambiguous += 1; }
+        }
+
+        let list = args.is_present("list");
+        let list_path = args.value_of("list_path");
+        // a list on stdout replaces the summary, so the output stays parseable
+        if !list || list_path.is_some() {
+            let mut out = Output::new(Some(args));
+            Self::print_summary(&mut out, "encoding", &by_encoding);
+            Self::print_summary(&mut out, "extension", &by_extension);
+            Self::print_summary(&mut out, "line endings", &by_eol);
+            out.text(format!("Files: {}, With BOM: {}, Ambiguous: {}", records.len(), with_bom, ambiguous));
+        }
+
+        if list {
+            let mut out = if let Some(list_path) = list_path {
+                let file = std::io::BufWriter::new(std::fs::File::create(list_path)?);
+                Output::with_writer(EFormat::from_args(Some(args)), Box::new(file))
+            } else {
+                Output::new(Some(args))
+            };
+            Self::print_list(&mut out, &records);
+        }
+        Ok(())
+    }
+}
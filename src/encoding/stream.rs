@@ -0,0 +1,420 @@
+use std::io::{Read, Write};
+
+use encoding::types::{DecoderTrap, EncoderTrap, EncodingRef, RawDecoder, RawEncoder};
+
+use crate::common;
+
+use super::eol::EolConverter;
+use super::traps;
+use super::traps::{Issue, Position};
+
+/// Path argument meaning stdin for sources and stdout for targets.
+pub(super) const STDIO_PATH: &str = "-";
+
+/// Size of the blocks files are read and converted in.
+pub(super) const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Bytes of previous chunks kept to report sequences split across chunk boundaries.
+const TAIL_SIZE: usize = 16;
+
+/// Incremental decoder which can be fed with arbitrary slices of the input.
+/// Multi-byte sequences split between two `feed` calls are kept inside the raw decoder.
+pub(super) struct StreamDecoder {
+    decoder: Box<dyn RawDecoder>,
+    trap: DecoderTrap,
+    // absolute offset of the next byte to feed
+    offset: usize,
+    // absolute offset of the bytes kept inside the raw decoder
+    pending: Option<usize>,
+    // last bytes before `offset`
+    tail: Vec<u8>,
+    position: Position,
+}
+
+impl StreamDecoder {
+    pub(super) fn new(e: EncodingRef, trap: DecoderTrap, base_offset: usize) -> Self {
+        Self {
+            decoder: e.raw_decoder(),
+            trap,
+            offset: base_offset,
+            pending: None,
+            tail: Vec::new(),
+            position: Position::new(),
+        }
+    }
+    /// Bytes in the absolute range `from..to` out of the tail and the current chunk.
+    fn bytes(&self, chunk: &[u8], from: usize, to: usize) -> Vec<u8> {
+        let tail_start = self.offset - self.tail.len();
+        (from.max(tail_start)..to)
+            .map(|i| if i < self.offset { self.tail[i - tail_start] } else { chunk[i - self.offset] })
+            .collect()
+    }
+    fn sync_position(&mut self, output: &str) {
+        if output.len() < self.position.scanned() { self.position.restart(); }
+        self.position.advance(output, output.len());
+    }
+    fn apply_trap(
+        &mut self,
+        chunk: &[u8],
+        from: usize,
+        to: usize,
+        output: &mut String,
+        issues: &mut Vec<Issue>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let bytes = self.bytes(chunk, from, to);
+        self.sync_position(output);
+        let issue = Issue {
+            stage: "decode",
+            kind: traps::decoder_action(&self.trap),
+            offset: from,
+            line: self.position.line,
+            column: self.position.column,
+            input: traps::hex(&bytes),
+        };
+        if !self.trap.trap(&mut *self.decoder, &bytes, output) {
+            return Err(common::errors::ErrorString::new(issue.to_string()));
+        }
+        self.pending = None;
+        issues.push(issue);
+        Ok(())
+    }
+    /// Moves `offset` back to `to`, the bytes after it will be fed again with the next chunk.
+    fn rewind(&mut self, to: usize, chunk: &[u8]) -> Vec<u8> {
+        let mut data = self.bytes(chunk, to, self.offset);
+        data.extend_from_slice(chunk);
+        let keep = self.tail.len().saturating_sub(self.offset - to);
+        self.tail.truncate(keep);
+        self.offset = to;
+        data
+    }
+    fn consume(&mut self, chunk: &[u8]) {
+        self.tail.extend_from_slice(&chunk[chunk.len().saturating_sub(TAIL_SIZE)..]);
+        let extra = self.tail.len().saturating_sub(TAIL_SIZE);
+        self.tail.drain(..extra);
+        self.offset += chunk.len();
+    }
+    pub(super) fn feed(&mut self, chunk: &[u8], output: &mut String, issues: &mut Vec<Issue>) -> Result<(), Box<dyn std::error::Error>> {
+        self.sync_position(output);
+        let mut data = std::borrow::Cow::Borrowed(chunk);
+        let mut remaining = 0;
+        loop {
+            let (processed, err) = self.decoder.raw_feed(&data[remaining..], output);
+            let unprocessed =
+                match self.pending { Some(pending) if processed == 0 => pending, _ => self.offset + remaining + processed };
+            if err.is_none() {
+                self.pending = if remaining + processed < data.len() { Some(unprocessed) } else { None };
+                break;
+            }
+
+            let end = (self.offset as isize + remaining as isize + err.unwrap().upto) as usize;
+            self.apply_trap(&data, unprocessed, end, output, issues)?;
+            if end < self.offset {
+                // the error ended inside bytes fed with a previous chunk
+                data = std::borrow::Cow::Owned(self.rewind(end, &data));
+                remaining = 0;
+            } else {
+                remaining = end - self.offset;
+            }
+        }
+        self.consume(&data);
+        self.sync_position(output);
+        Ok(())
+    }
+    pub(super) fn finish(&mut self, output: &mut String, issues: &mut Vec<Issue>) -> Result<(), Box<dyn std::error::Error>> {
+        loop {
+            let err = self.decoder.raw_finish(output);
+            if err.is_none() { return Ok(()); }
+
+            let end = (self.offset as isize + err.unwrap().upto) as usize;
+            let unprocessed = self.pending.unwrap_or(end);
+            self.apply_trap(&[], unprocessed, end, output, issues)?;
+            if end < self.offset {
+                let data = self.rewind(end, &[]);
+                self.feed(&data, output, issues)?;
+            }
+        }
+    }
+}
+
+/// FNV-1a hash and length of a text fed in any number of pieces.
+#[derive(Clone, Copy, PartialEq)]
+pub(super) struct TextDigest {
+    hash: u64,
+    chars: usize,
+}
+
+impl TextDigest {
+    pub(super) fn new() -> Self {
+        Self { hash: 0xcbf2_9ce4_8422_2325, chars: 0 }
+    }
+    pub(super) fn update(&mut self, text: &str) {
+        for b in text.bytes() {
+            self.hash = (self.hash ^ b as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+        self.chars += text.chars().count();
+    }
+    pub(super) fn chars(&self) -> usize {
+        self.chars
+    }
+}
+
+/// Incremental encoder, the text can be fed in any number of pieces.
+pub(super) struct StreamEncoder {
+    encoder: Box<dyn RawEncoder>,
+    trap: EncoderTrap,
+    // bytes produced by previous `feed` calls
+    written: usize,
+    position: Position,
+    // everything fed so far, to verify the output decodes back to it
+    digest: TextDigest,
+}
+
+impl StreamEncoder {
+    pub(super) fn new(e: EncodingRef, trap: EncoderTrap) -> Self {
+        Self {
+            encoder: e.raw_encoder(),
+            trap,
+            written: 0,
+            position: Position::new(),
+            digest: TextDigest::new(),
+        }
+    }
+    fn apply_trap(
+        &mut self,
+        input: &str,
+        written: usize,
+        output: &mut Vec<u8>,
+        issues: &mut Vec<Issue>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let issue = Issue {
+            stage: "encode",
+            kind: traps::encoder_action(&self.trap),
+            offset: written,
+            line: self.position.line,
+            column: self.position.column,
+            input: format!("{:?}", input),
+        };
+        if !self.trap.trap(&mut *self.encoder, input, output) {
+            return Err(common::errors::ErrorString::new(issue.to_string()));
+        }
+        issues.push(issue);
+        Ok(())
+    }
+    pub(super) fn digest(&self) -> TextDigest {
+        self.digest
+    }
+    pub(super) fn feed(&mut self, input: &str, output: &mut Vec<u8>, issues: &mut Vec<Issue>) -> Result<(), Box<dyn std::error::Error>> {
+        self.digest.update(input);
+        let start = output.len();
+        let mut remaining = 0;
+        while remaining < input.len() {
+            let (processed, err) = self.encoder.raw_feed(&input[remaining..], output);
+            if err.is_none() { break; }
+
+            let unprocessed = remaining + processed;
+            // encoders never keep whole characters between calls, so the error is always in this piece
+            let end = (remaining as isize + err.unwrap().upto).max(unprocessed as isize) as usize;
+            self.position.advance(input, unprocessed);
+            let written = self.written + output.len() - start;
+            self.apply_trap(&input[unprocessed..end], written, output, issues)?;
+            remaining = end;
+        }
+        self.position.advance(input, input.len());
+        self.position.restart();
+        self.written += output.len() - start;
+        Ok(())
+    }
+    pub(super) fn finish(&mut self, output: &mut Vec<u8>, issues: &mut Vec<Issue>) -> Result<(), Box<dyn std::error::Error>> {
+        let start = output.len();
+        let err = self.encoder.raw_finish(output);
+        if err.is_some() {
+            let written = self.written + output.len() - start;
+            self.apply_trap("", written, output, issues)?;
+        }
+        self.written += output.len() - start;
+        Ok(())
+    }
+}
+
+/// Converts `reader` into `writer` chunk by chunk, so memory use does not depend on the input size.
+/// Line endings are rewritten by `eol` if given. `progress` is called with the number of bytes read so far.
+pub(super) fn transcode(
+    reader: &mut dyn Read,
+    writer: &mut dyn Write,
+    decoder: &mut StreamDecoder,
+    encoder: &mut StreamEncoder,
+    mut eol: Option<&mut EolConverter>,
+    issues: &mut Vec<Issue>,
+    progress: &mut dyn FnMut(u64),
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    let mut text = String::new();
+    let mut converted = String::new();
+    let mut bytes = Vec::<u8>::new();
+    let mut total = 0u64;
+
+    loop {
+        let size = reader.read(&mut chunk)?;
+        if size == 0 { break; }
+        total += size as u64;
+
+        decoder.feed(&chunk[..size], &mut text, issues)?;
+        if let Some(eol) = eol.as_mut() {
+            eol.convert(&text, &mut converted);
+            std::mem::swap(&mut text, &mut converted);
+            converted.clear();
+        }
+        encoder.feed(&text, &mut bytes, issues)?;
+        writer.write_all(&bytes)?;
+        text.clear();
+        bytes.clear();
+        progress(total);
+    }
+
+    decoder.finish(&mut text, issues)?;
+    if let Some(eol) = eol {
+        eol.convert(&text, &mut converted);
+        eol.finish(&mut converted);
+        std::mem::swap(&mut text, &mut converted);
+    }
+    encoder.feed(&text, &mut bytes, issues)?;
+    encoder.finish(&mut bytes, issues)?;
+    writer.write_all(&bytes)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Opens a file or stdin, also returns the input size or 0 if it is unknown.
+pub(super) fn open_input(path: &str) -> std::io::Result<(Box<dyn Read>, u64)> {
+    if path == STDIO_PATH {
+        return Ok((Box::new(std::io::stdin()), 0));
+    }
+    let file = std::fs::File::open(path)?;
+    let size = file.metadata()?.len();
+    Ok((Box::new(file), size))
+}
+
+pub(super) fn display_name(path: &str) -> &str {
+    if path == STDIO_PATH { "<stdin>" } else { path }
+}
+
+/// Reads up to `size` bytes from the start of the reader.
+pub(super) fn read_sample(reader: &mut dyn Read, size: usize) -> std::io::Result<Vec<u8>> {
+    let mut sample = Vec::<u8>::new();
+    reader.take(size as u64).read_to_end(&mut sample)?;
+    Ok(sample)
+}
+
+/// Files smaller than this are converted without progress output.
+const PROGRESS_MIN_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Prints the percentage of a big file processed so far to stderr.
+pub(super) struct Progress<'a> {
+    name: &'a str,
+    total: u64,
+    percent: Option<u64>,
+}
+
+impl<'a> Progress<'a> {
+    pub(super) fn new(name: &'a str, total: u64) -> Self {
+        Self { name, total, percent: None }
+    }
+    pub(super) fn update(&mut self, done: u64) {
+        if self.total < PROGRESS_MIN_SIZE { return; }
+        let percent = done * 100 / self.total;
+        if self.percent == Some(percent) { return; }
+        self.percent = Some(percent);
+        eprint!("\r{}: {}%", self.name, percent);
+    }
+    pub(super) fn finish(&self) {
+        if self.percent.is_some() { eprintln!(); }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use encoding::types::DecoderTrap;
+
+    use super::super::codecs;
+    use super::super::traps::Issue;
+    use super::StreamDecoder;
+
+    fn decode(decoder: &mut StreamDecoder, chunks: &[&[u8]]) -> (String, Vec<Issue>) {
+        let mut text = String::new();
+        let mut issues = Vec::new();
+        for chunk in chunks {
+            decoder.feed(chunk, &mut text, &mut issues).unwrap();
+        }
+        decoder.finish(&mut text, &mut issues).unwrap();
+        (text, issues)
+    }
+
+    #[test]
+    fn multi_byte_sequence_split_between_chunks() {
+        let mut decoder = StreamDecoder::new(encoding::all::UTF_8, DecoderTrap::Strict, 0);
+        let (text, issues) = decode(&mut decoder, &[b"a\xC3", b"\xA9\n\xD0", b"\xB6"]);
+        assert_eq!(text, "aé\nж");
+        assert!(issues.is_empty());
+    }
+
+    #[test]
+    fn invalid_sequence_spanning_chunks() {
+        let mut decoder = StreamDecoder::new(encoding::all::UTF_8, DecoderTrap::Replace, 0);
+        let (text, issues) = decode(&mut decoder, &[b"x\nab\xE2", b"\x82c"]);
+        assert_eq!(text, "x\nab\u{FFFD}c");
+        assert_eq!(issues.len(), 1);
+        assert_eq!((issues[0].offset, issues[0].line, issues[0].column), (4, 2, 3));
+        assert_eq!(issues[0].input, "E2 82");
+    }
+
+    #[test]
+    fn strict_trap_fails_on_sequence_spanning_chunks() {
+        let mut decoder = StreamDecoder::new(encoding::all::UTF_8, DecoderTrap::Strict, 0);
+        let mut text = String::new();
+        let mut issues = Vec::new();
+        decoder.feed(b"ab\xE2", &mut text, &mut issues).unwrap();
+        let result = decoder.feed(b"\x82c", &mut text, &mut issues);
+        assert!(result.is_err());
+        assert!(result.err().unwrap().to_string().contains("byte 2, line 1, column 3: E2 82"));
+    }
+
+    #[test]
+    fn error_ending_in_previous_chunk_rewinds() {
+        // 81 30 starts a four byte sequence, the error is reported for 81 alone and 30 is decoded again
+        let mut decoder = StreamDecoder::new(encoding::all::GB18030, DecoderTrap::Replace, 0);
+        let (text, issues) = decode(&mut decoder, &[b"\x81\x30", b"\x81\x41\xFF"]);
+        assert_eq!(text, "\u{FFFD}0\u{4E04}\u{FFFD}");
+        assert_eq!(issues.len(), 2);
+        assert_eq!((issues[0].offset, issues[0].line, issues[0].column), (0, 1, 1));
+        assert_eq!(issues[0].input, "81");
+        assert_eq!((issues[1].offset, issues[1].line, issues[1].column), (4, 1, 4));
+        assert_eq!(issues[1].input, "FF");
+    }
+
+    #[test]
+    fn utf32_pending_bytes() {
+        // offsets count the BOM which is not fed
+        let mut decoder = StreamDecoder::new(&codecs::UTF_32LE, DecoderTrap::Replace, 4);
+        let (text, issues) = decode(&mut decoder, &[b"\x41\x00", b"\x00\x00\x00\x00", b"\x11\x00\x42\x00"]);
+        assert_eq!(text, "A\u{FFFD}\u{FFFD}");
+        assert_eq!(issues.len(), 2);
+        assert_eq!((issues[0].offset, issues[0].line, issues[0].column), (8, 1, 2));
+        assert_eq!(issues[0].input, "00 00 11 00");
+        assert_eq!((issues[1].offset, issues[1].line, issues[1].column), (12, 1, 3));
+        assert_eq!(issues[1].input, "42 00");
+    }
+
+    #[test]
+    fn position_continues_when_output_is_cleared() {
+        let mut decoder = StreamDecoder::new(encoding::all::UTF_8, DecoderTrap::Replace, 0);
+        let mut text = String::new();
+        let mut issues = Vec::new();
+        decoder.feed(b"one\ntw", &mut text, &mut issues).unwrap();
+        text.clear();
+        decoder.feed(b"o\xFF", &mut text, &mut issues).unwrap();
+        decoder.finish(&mut text, &mut issues).unwrap();
+        assert_eq!(text, "o\u{FFFD}");
+        assert_eq!(issues.len(), 1);
+        assert_eq!((issues[0].offset, issues[0].line, issues[0].column), (7, 2, 4));
+    }
+}
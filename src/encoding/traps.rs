@@ -0,0 +1,92 @@
+use encoding::types::{DecoderTrap, EncoderTrap};
+
+use crate::common;
+
+/// Character that was replaced, dropped or escaped by a non-strict trap.
+pub(super) struct Issue {
+    pub(super) stage: &'static str,
+    pub(super) kind: &'static str,
+    pub(super) offset: usize,
+    pub(super) line: usize,
+    pub(super) column: usize,
+    pub(super) input: String,
+}
+
+impl std::fmt::Display for Issue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let file = if self.stage == "decode" { "source" } else { "target" };
+        write!(f, "{}: {} at {} byte {}, line {}, column {}: {}",
+               self.stage, self.kind, file, self.offset, self.line, self.column, self.input)
+    }
+}
+
+/// Line and column of the end of the text seen so far.
+pub(super) struct Position {
+    pub(super) line: usize,
+    pub(super) column: usize,
+    scanned: usize,
+}
+
+impl Position {
+    pub(super) fn new() -> Self {
+        Self { line: 1, column: 1, scanned: 0 }
+    }
+    pub(super) fn advance(&mut self, text: &str, upto: usize) {
+        for c in text[self.scanned..upto].chars() {
+            if c == '\n' {
+                self.line += 1;
+                self.column = 1;
+            } else {
+                self.column += 1;
+            }
+        }
+        self.scanned = upto;
+    }
+    pub(super) fn scanned(&self) -> usize {
+        self.scanned
+    }
+    /// Continues counting on a new piece of text.
+    pub(super) fn restart(&mut self) {
+        self.scanned = 0;
+    }
+}
+
+pub(super) fn parse_decoder_trap(name: &str) -> Result<DecoderTrap, Box<dyn std::error::Error>> {
+    match name {
+        "strict" => Ok(DecoderTrap::Strict),
+        "replace" => Ok(DecoderTrap::Replace),
+        "ignore" => Ok(DecoderTrap::Ignore),
+        _ => Err(common::errors::ErrorString::new(format!("Unknown decoder trap {}", name))),
+    }
+}
+
+pub(super) fn parse_encoder_trap(name: &str) -> Result<EncoderTrap, Box<dyn std::error::Error>> {
+    match name {
+        "strict" => Ok(EncoderTrap::Strict),
+        "replace" => Ok(EncoderTrap::Replace),
+        "ignore" => Ok(EncoderTrap::Ignore),
+        "ncr" | "html" => Ok(EncoderTrap::NcrEscape),
+        _ => Err(common::errors::ErrorString::new(format!("Unknown encoder trap {}", name))),
+    }
+}
+
+pub(super) fn decoder_action(trap: &DecoderTrap) -> &'static str {
+    match trap {
+        DecoderTrap::Strict => "invalid sequence",
+        DecoderTrap::Ignore => "dropped",
+        _ => "replaced",
+    }
+}
+
+pub(super) fn encoder_action(trap: &EncoderTrap) -> &'static str {
+    match trap {
+        EncoderTrap::Strict => "unmappable character",
+        EncoderTrap::Ignore => "dropped",
+        EncoderTrap::NcrEscape => "escaped",
+        _ => "replaced",
+    }
+}
+
+pub(super) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(" ")
+}
@@ -0,0 +1,174 @@
+use std::io::Read;
+
+use crate::common;
+use crate::common::output::{Output, Record};
+
+use super::detect;
+use super::encoding::EncodingRef;
+use super::labels;
+use super::stream;
+use super::traps::{Issue, Position};
+
+/// Exit code when the files decode to different text.
+const EXIT_MISMATCH: i32 = 1;
+
+/// Characters shown from each side of the first difference.
+const SNIPPET_LEN: usize = 20;
+
+/// Text of a file decoded chunk by chunk, invalid sequences are errors.
+struct DecodedFile<'a> {
+    name: &'a str,
+    reader: Box<dyn Read>,
+    decoder: stream::StreamDecoder,
+    chunk: Vec<u8>,
+    text: String,
+    eof: bool,
+}
+
+impl<'a> DecodedFile<'a> {
+    /// With `skip_bom` a BOM of the encoding is not a part of the text.
+    fn open(path: &'a str, e: EncodingRef, skip_bom: bool) -> Result<Self, Box<dyn std::error::Error>> {
+        let (mut reader, _) = stream::open_input(path)?;
+        let head = stream::read_sample(&mut reader, 4)?;
+        let bom_len = if skip_bom { detect::bom_len(&head, &e) } else { 0 };
+        Ok(Self {
+            name: stream::display_name(path),
+            reader: Box::new(std::io::Cursor::new(head[bom_len..].to_vec()).chain(reader)),
+            decoder: stream::StreamDecoder::new(e, encoding::types::DecoderTrap::Strict, bom_len),
+            chunk: vec![0u8; stream::CHUNK_SIZE],
+            text: String::new(),
+            eof: false,
+        })
+    }
+    /// Appends the next decoded chunk to `text`.
+    fn fill(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut issues = Vec::<Issue>::new();
+        let size = self.reader.read(&mut self.chunk)?;
+        let res =
+            if size == 0 { self.eof = true; self.decoder.finish(&mut self.text, &mut issues) }
+            else { self.decoder.feed(&self.chunk[..size], &mut self.text, &mut issues) };
+        if let Err(err) = res {
+            return Err(common::errors::ErrorString::new(format!("{}: {}", self.name, err)));
+        }
+        Ok(())
+    }
+}
+
+struct Mismatch {
+    line: usize,
+    column: usize,
+    expected: String,
+    found: String,
+}
+
+impl std::fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "text differs at line {}, column {}: expected {:?}, found {:?}", self.line, self.column, self.expected, self.found)
+    }
+}
+
+pub struct VerifyCmd;
+
+impl VerifyCmd {
+    fn common_prefix(a: &str, b: &str) -> usize {
+        a.char_indices().zip(b.chars()).find(|((_, ca), cb)| ca != cb).map_or(a.len().min(b.len()), |((i, _), _)| i)
+    }
+    fn snippet(text: &str) -> String {
+        let end = text.find('\n').unwrap_or(text.len());
+        let snippet: String = text[..end].chars().take(SNIPPET_LEN).collect();
+        if text.is_empty() { String::from("<end of file>") } else { snippet }
+    }
+    /// Decodes both files side by side and returns the first difference of their text.
+    fn compare(src: &mut DecodedFile, dst: &mut DecodedFile) -> Result<Option<Mismatch>, Box<dyn std::error::Error>> {
+        let mut position = Position::new();
+        loop {
+            if !src.eof && src.text.len() <= dst.text.len() {
+                src.fill()?;
+            } else if !dst.eof && dst.text.len() <= src.text.len() {
+                dst.fill()?;
+            }
+            let common = Self::common_prefix(&src.text, &dst.text);
+            position.advance(&src.text, common);
+            position.restart();
+            src.text.drain(..common);
+            dst.text.drain(..common);
+
+            // after the common part is dropped at most one side has text left
+            let differs = !src.text.is_empty() && !dst.text.is_empty();
+            let ended = (src.eof && src.text.is_empty() && !dst.text.is_empty())
+                || (dst.eof && dst.text.is_empty() && !src.text.is_empty());
+            if differs || ended {
+                return Ok(Some(Mismatch {
+                    line: position.line,
+                    column: position.column,
+                    expected: Self::snippet(&src.text),
+                    found: Self::snippet(&dst.text),
+                }));
+            }
+            if src.eof && dst.eof { return Ok(None); }
+        }
+    }
+    /// Digest of the text a file decodes to, used to verify a conversion.
+    pub(super) fn digest(path: &str, e: EncodingRef, skip_bom: bool) -> Result<stream::TextDigest, Box<dyn std::error::Error>> {
+        let mut file = DecodedFile::open(path, e, skip_bom)?;
+        let mut digest = stream::TextDigest::new();
+        while !file.eof {
+            file.fill()?;
+            digest.update(&file.text);
+            file.text.clear();
+        }
+        Ok(digest)
+    }
+}
+
+impl common::Command for VerifyCmd {
+    fn create() -> Box<Self> { Box::<Self>::new(Self {}) }
+    fn name() -> &'static str { "verify" }
+    fn fill_subcommand<'a, 'b>(&self, app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+        app.subcommand(
+            clap::App::new(Self::name())
+                .about("checks that a converted file has the same text as the source")
+                .arg(clap::Arg::with_name("src_path").required(true).help("source file, - for stdin"))
+                .arg(clap::Arg::with_name("dst_path").required(true).help("converted file"))
+                .arg(
+                    clap::Arg::with_name("source_codepage")
+                        .long("src_codepage")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Encoding label or Windows code page of the source"))
+                .arg(
+                    clap::Arg::with_name("target_codepage")
+                        .long("tgt_codepage")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Encoding label or Windows code page of the converted file"))
+        )
+    }
+    fn run(&self, args: Option<&clap::ArgMatches>) -> Result<(), Box<dyn std::error::Error>> {
+        let args = args.unwrap();
+        let src_path = args.value_of("src_path").unwrap();
+        let dst_path = args.value_of("dst_path").unwrap();
+        let decoder_ref = labels::parse_encoding(args.value_of("source_codepage").unwrap())?;
+        let encoder_ref = labels::parse_encoding(args.value_of("target_codepage").unwrap())?;
+        if dst_path == stream::STDIO_PATH {
+            return Err(common::errors::ErrorStr::new("Only the source file can be read from stdin"));
+        }
+
+        let mut src = DecodedFile::open(src_path, decoder_ref, true)?;
+        let mut dst = DecodedFile::open(dst_path, encoder_ref, true)?;
+        match Self::compare(&mut src, &mut dst)? {
+            Some(mismatch) => Err(common::errors::ExitError::new(EXIT_MISMATCH,
+                format!("{} ({}) and {} ({}): {}", src.name, decoder_ref.name(), dst.name, encoder_ref.name(), mismatch))),
+            None => {
+                Output::new(Some(args)).record(Record::new()
+                    .field("source", src.name)
+                    .field("source_encoding", decoder_ref.name())
+                    .field("target", dst.name)
+                    .field("target_encoding", encoder_ref.name())
+                    .field("same", true)
+                    .text(format!("Same text. Source file: {} ({}), Target file: {} ({})", src.name, decoder_ref.name(), dst.name, encoder_ref.name())));
+                Ok(())
+            }
+        }
+    }
+}
@@ -4,6 +4,7 @@ use winapi::_core::ptr::null_mut;
 
 use crate::common;
 use crate::common::errors::ErrorString;
+use crate::common::output::{Output, Record};
 
 #[derive(Default)]
 struct WCharString {
@@ -116,10 +117,15 @@ impl common::Command for ListFileHolders {
             }
 
             processes.resize(pn_proc_info_needed as usize, std::mem::MaybeUninit::uninit().assume_init());
-            println!("Holders count: {}", pn_proc_info_needed);
-            println!("{:>6}: {}", "PID", "Process name");
+            let mut out = Output::new(Some(args));
+            out.text(format!("Holders count: {}", pn_proc_info_needed));
+            out.text(format!("{:>6}: {}", "PID", "Process name"));
             for p in processes.iter() {
-                println!("{:>6}: {}", p.Process.dwProcessId, decode_utf16(&p.strAppName));
+                let name = decode_utf16(&p.strAppName);
+                out.record(Record::new()
+                    .field("pid", p.Process.dwProcessId)
+                    .field("name", &name)
+                    .text(format!("{:>6}: {}", p.Process.dwProcessId, name)));
             }
 
             RmEndSession(session_handle);
@@ -1,4 +1,5 @@
 use crate::common;
+use crate::common::output::{Output, Record};
 use crate::fs::list_file_holders::ListFileHolders;
 
 mod list_file_holders;
@@ -57,15 +58,17 @@ impl common::Command for ListDirCmd {
         let args = args.unwrap();
         let path = args.value_of("path").unwrap();
         let mut explorer = FileExplorer::create(path, false, false)?;
+        let mut out = Output::new(Some(args));
 
         while !explorer.eof() {
             let file = explorer.next()?;
             let filename = file.m_path.file_name().unwrap().to_str().unwrap();
             //let filename = file.m_path.to_str().unwrap();
+            let record = Record::new().field("name", filename);
             if file.m_meta.is_dir() {
-                println!("[{}]", filename);
+                out.record(record.field("type", "dir").text(format!("[{}]", filename)));
             } else {
-                println!("{}", filename);
+                out.record(record.field("type", "file").text(filename));
             }
         }
         Ok(())
@@ -19,6 +19,7 @@ fn main() {
             .version("0.1")
             .about("windows os help utils")
             .author("vaiz");
+    app = disp.fill_global_args(app);
     app = disp.fill_subcommands(app);
 
     let matches = app.get_matches();
@@ -31,7 +32,13 @@ fn main() {
 
     let result = disp.run(cmd_name, args);
     if result.is_err() {
+        let err = result.err().unwrap();
+        if let Some(exit_error) = err.downcast_ref::<common::errors::ExitError>() {
+            eprintln!("{}", err);
+            std::process::exit(exit_error.code());
+        }
         eprintln!("Command '{}' finished with error", cmd_name);
-        eprintln!("{}", result.err().unwrap());
+        eprintln!("{}", err);
+        std::process::exit(1);
     }
 }
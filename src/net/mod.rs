@@ -3,6 +3,7 @@ extern crate netstat;
 use netstat::*;
 
 use crate::common;
+use crate::common::output::{Output, Record};
 
 pub struct NetDispatcher {
     m_disp: common::Dispatcher,
@@ -64,24 +65,39 @@ impl common::Command for ListAllConnectionCmd {
 
 
         let sockets_info = get_sockets_info(af_flags, proto_flags)?;
+        let mut out = Output::new(Some(args));
         for si in sockets_info {
             if !ListAllConnectionCmd::is_port_match(&si, &port) { continue; }
 
-            match si.protocol_socket_info {
-                ProtocolSocketInfo::Tcp(tcp_si) => println!(
-                    "TCP {}:{} -> {}:{} {:?} - {}",
-                    tcp_si.local_addr,
-                    tcp_si.local_port,
-                    tcp_si.remote_addr,
-                    tcp_si.remote_port,
-                    si.associated_pids,
-                    tcp_si.state
-                ),
-                ProtocolSocketInfo::Udp(udp_si) => println!(
-                    "UDP {}:{} -> *:* {:?}",
-                    udp_si.local_addr, udp_si.local_port, si.associated_pids
-                ),
-            }
+            let record = match si.protocol_socket_info {
+                ProtocolSocketInfo::Tcp(tcp_si) => Record::new()
+                    .field("protocol", "TCP")
+                    .field("local_addr", tcp_si.local_addr.to_string())
+                    .field("local_port", tcp_si.local_port)
+                    .field("remote_addr", tcp_si.remote_addr.to_string())
+                    .field("remote_port", tcp_si.remote_port)
+                    .field("pids", si.associated_pids.clone())
+                    .field("state", tcp_si.state.to_string())
+                    .text(format!(
+                        "TCP {}:{} -> {}:{} {:?} - {}",
+                        tcp_si.local_addr,
+                        tcp_si.local_port,
+                        tcp_si.remote_addr,
+                        tcp_si.remote_port,
+                        si.associated_pids,
+                        tcp_si.state
+                    )),
+                ProtocolSocketInfo::Udp(udp_si) => Record::new()
+                    .field("protocol", "UDP")
+                    .field("local_addr", udp_si.local_addr.to_string())
+                    .field("local_port", udp_si.local_port)
+                    .field("pids", si.associated_pids.clone())
+                    .text(format!(
+                        "UDP {}:{} -> *:* {:?}",
+                        udp_si.local_addr, udp_si.local_port, si.associated_pids
+                    )),
+            };
+            out.record(record);
         }
         Ok(())
     }